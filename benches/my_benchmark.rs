@@ -1,22 +1,139 @@
-use chat_spy::match_pattern::{MatchMode, MatchPattern};
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-
-fn criterion_benchmark(c: &mut Criterion) {
-    let mut p = MatchPattern::from([
-        "Kek",
-        "jopa",
-        "jejejeje",
-        "underasd",
-        "sdfsdfsd",
-        "sasdasd",
-        "sdfsfsf",
-        "sdfsssfsfsdf",
-        "fsfsfsfsfdsf",
-        "ssffsfsfsfs",
-        "sdfs",
-    ]);
-    p.set_mode(MatchMode::Inclusive);
-    let s = "Some sTrIng to bench format_word fn sdsdfsdf sdf sdf sdf sdf sdf sdf sdfdsdssdfdsdf Hfjdfhdsfods Ssido sa dfdkds KSsdkf nsdf jksdf sdlkf sdf sdf ".to_owned();
+use chatspy::match_pattern::normalize::Normalization;
+use chatspy::match_pattern::{MatchMode, MatchPattern};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const MESSAGES: [&str; 4] = [
+    "Some sTrIng to bench format_word fn sdsdfsdf sdf sdf sdf sdf sdf sdf sdfdsdssdfdsdf Hfjdfhdsfods Ssido sa dfdkds KSsdkf nsdf jksdf sdlkf sdf sdf ",
+    "LUL that was such a throw from the radiant side, gg wp everyone",
+    "anyone know what keyboard he is using? the sound is insane",
+    "KEKW KEKW KEKW KEKW he really did not see the smoke coming",
+];
+
+// Tokens a Twitch chat is made of: emotes, mentions, shouting, numbers,
+// punctuation, links and a bit of Cyrillic
+const CHAT_TOKENS: [&str; 48] = [
+    "KEKW",
+    "LUL",
+    "OMEGALUL",
+    "PogChamp",
+    "Kappa",
+    "monkaS",
+    "Sadge",
+    "catJAM",
+    "gg",
+    "GG",
+    "wp",
+    "ez",
+    "?",
+    "??",
+    "!!!",
+    "lol",
+    "LMAO",
+    "xD",
+    ":)",
+    "the",
+    "he",
+    "is",
+    "so",
+    "bad",
+    "what",
+    "was",
+    "that",
+    "no",
+    "way",
+    "actually",
+    "insane",
+    "throw",
+    "clip",
+    "it",
+    "1v5",
+    "2023",
+    "100%",
+    "@streamer",
+    "@mod_team",
+    "chat,",
+    "bro...",
+    "W",
+    "L",
+    "https://clips.twitch.tv/abc",
+    "ахахах",
+    "привет",
+    "ЛОЛ",
+    "ну",
+];
+
+// Deterministic messages of 1 to 16 chat tokens
+fn chat_corpus(n: usize) -> Vec<String> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    (0..n)
+        .map(|_| {
+            let len = 1 + next() % 16;
+            (0..len)
+                .map(|_| CHAT_TOKENS[(next() % CHAT_TOKENS.len() as u64) as usize])
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+// Deterministic pseudo-random lowercase words, so every run uses the same list
+fn wordlist(n: usize) -> Vec<String> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let len = 4 + (state % 8) as usize;
+            (0..len)
+                .map(|i| (b'a' + ((state >> (i * 5)) % 26) as u8) as char)
+                .collect()
+        })
+        .collect()
+}
+
+// Word by word scan over byte windows which `MatchMode::Inclusive` used to do
+fn naive_inclusive(words: &[String], s: &str) -> bool {
+    s.split(' ').map(|w| w.to_lowercase()).any(|w| {
+        words.iter().any(|pattern_w| {
+            w.as_bytes()
+                .windows(pattern_w.len())
+                .any(|sub_w| sub_w == pattern_w.as_bytes())
+        })
+    })
+}
+
+// Linear scan over the list which `MatchMode::Exclusive` used to do per token
+fn naive_exclusive(words: &[String], s: &str) -> bool {
+    s.split_whitespace()
+        .map(|w| w.to_lowercase())
+        .any(|w| words.contains(&w))
+}
+
+fn format_word_benchmark(c: &mut Criterion) {
+    let p = MatchPattern::builder()
+        .mode(MatchMode::Inclusive)
+        .words([
+            "Kek",
+            "jopa",
+            "jejejeje",
+            "underasd",
+            "sdfsdfsd",
+            "sasdasd",
+            "sdfsfsf",
+            "sdfsssfsfsdf",
+            "fsfsfsfsfdsf",
+            "ssffsfsfsfs",
+            "sdfs",
+        ])
+        .build();
+    let s = MESSAGES[0].to_owned();
     c.bench_function("format_word", |b| {
         b.iter(|| {
             for _ in 0..30_000 {
@@ -26,5 +143,112 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+fn inclusive_wordlist_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("inclusive_wordlist");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(MESSAGES.len() as u64));
+
+    for n in [1_000, 10_000, 100_000] {
+        let words = wordlist(n);
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Inclusive)
+            .words(words.iter().map(String::as_str))
+            .build();
+
+        group.bench_with_input(BenchmarkId::new("naive", n), &words, |b, words| {
+            b.iter(|| {
+                for m in MESSAGES {
+                    black_box(naive_inclusive(words, m));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("aho_corasick", n), &p, |b, p| {
+            b.iter(|| {
+                for m in MESSAGES {
+                    black_box(p.match_str(m));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn exclusive_wordlist_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exclusive_wordlist");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(MESSAGES.len() as u64));
+
+    for n in [1_000, 10_000, 100_000] {
+        let words = wordlist(n);
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .words(words.iter().map(String::as_str))
+            .build();
+
+        group.bench_with_input(BenchmarkId::new("naive", n), &words, |b, words| {
+            b.iter(|| {
+                for m in MESSAGES {
+                    black_box(naive_exclusive(words, m));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("hashed", n), &p, |b, p| {
+            b.iter(|| {
+                for m in MESSAGES {
+                    black_box(p.match_str(m));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// Messages per second through `match_str` with each kind of pattern. Almost
+// none of the messages match, so every one of them is tokenized in full.
+fn chat_corpus_benchmark(c: &mut Criterion) {
+    let corpus = chat_corpus(10_000);
+    let words = wordlist(1_000);
+    let mut group = c.benchmark_group("chat_corpus");
+    group.throughput(Throughput::Elements(corpus.len() as u64));
+
+    let patterns = [
+        (
+            "inclusive",
+            MatchPattern::builder().mode(MatchMode::Inclusive),
+        ),
+        (
+            "exclusive",
+            MatchPattern::builder().mode(MatchMode::Exclusive),
+        ),
+        ("prefix", MatchPattern::builder().mode(MatchMode::Prefix)),
+        (
+            "normalized",
+            MatchPattern::builder()
+                .mode(MatchMode::Exclusive)
+                .normalization(Normalization::all()),
+        ),
+    ];
+    for (name, builder) in patterns {
+        let p = builder.words(words.iter().map(String::as_str)).build();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for m in &corpus {
+                    black_box(p.match_str(m));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    format_word_benchmark,
+    inclusive_wordlist_benchmark,
+    exclusive_wordlist_benchmark,
+    chat_corpus_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,99 @@
+use crate::match_pattern::conditions::{ConditionMatcher, Conditions};
+use crate::match_pattern::MatchPattern;
+use crate::protocol::{BacktestReport, BacktestSample};
+use crate::twitch::UserMessage;
+
+// Checks messages against a pattern the way the processor would, but only
+// counts what it catches. The first `samples` hits are kept as they are.
+pub struct Backtest<'a> {
+    pattern: &'a MatchPattern,
+    filter: ConditionMatcher,
+    samples: usize,
+    report: BacktestReport,
+}
+
+impl<'a> Backtest<'a> {
+    pub fn new(pattern: &'a MatchPattern, filter: Conditions, samples: usize) -> Self {
+        Backtest {
+            pattern,
+            filter: ConditionMatcher::from(&filter),
+            samples,
+            report: BacktestReport::default(),
+        }
+    }
+
+    pub fn check(&mut self, msg: &UserMessage, time: Option<String>) {
+        if !self.filter.matches(msg) {
+            return;
+        }
+        self.report.checked += 1;
+
+        let Some(report) = self.pattern.match_message(msg) else {
+            return;
+        };
+        self.report.hits += 1;
+        *self.report.channels.entry(msg.channel.clone()).or_default() += 1;
+        if self.report.samples.len() < self.samples {
+            self.report.samples.push(BacktestSample {
+                channel: msg.channel.clone(),
+                author: msg.author.clone(),
+                message: msg.message.clone(),
+                time,
+                report,
+            });
+        }
+    }
+
+    pub fn finish(self) -> BacktestReport {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_pattern::conditions::NameCondition;
+
+    #[test]
+    fn backtest() {
+        let p = MatchPattern::builder()
+            .words(["scam", "free nitro"])
+            .build();
+        let filter = Conditions {
+            author: None,
+            channel: Some(NameCondition::List(vec![
+                "forsen".to_owned(),
+                "xqc".to_owned(),
+            ])),
+        };
+        let mut backtest = Backtest::new(&p, filter, 2);
+
+        let messages = [
+            ("forsen", "a", "free nitro here"),
+            ("forsen", "b", "hello"),
+            ("xqc", "c", "SCAM"),
+            ("xqc", "d", "another scam"),
+            ("esl_dota2", "e", "scam"),
+        ];
+        for (channel, author, message) in messages {
+            let msg = UserMessage {
+                channel: channel.to_owned(),
+                author: author.to_owned(),
+                message: message.to_owned(),
+            };
+            backtest.check(&msg, None);
+        }
+
+        let report = backtest.finish();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.hits, 3);
+        assert_eq!(report.channels["forsen"], 1);
+        assert_eq!(report.channels["xqc"], 2);
+        let authors = report
+            .samples
+            .iter()
+            .map(|s| s.author.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(authors, ["a", "c"]);
+    }
+}
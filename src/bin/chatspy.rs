@@ -1,7 +1,13 @@
-use chatspy::match_pattern::MatchMode;
+use chatspy::match_pattern::case::CaseMode;
+use chatspy::match_pattern::conditions::{Conditions, NameCondition};
+use chatspy::match_pattern::normalize::Normalization;
+use chatspy::match_pattern::stem::Language;
+use chatspy::match_pattern::{MatchMode, PatternConfig};
 use chatspy::protocol::*;
+use chatspy::storage::TwitchMessage;
 use chatspy::SOCKET_PATH;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 use tokio::io::Result as IoResult;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UnixStream;
@@ -12,16 +18,190 @@ struct Args {
     command: CliCommand,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum ModeArg {
+    Inclusive,
+    Exclusive,
+    Regex,
+    Fuzzy,
+    /// Tokens starting with a word
+    Prefix,
+    /// Tokens ending with a word
+    Suffix,
+    /// Words with '*' and '?' wildcards matched against whole tokens
+    Glob,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum CaseArg {
+    Sensitive,
+    Simple,
+    /// Unicode case folding, 'ß' matches 'ss'
+    Full,
+    /// Turkish and Azerbaijani dotted and dotless i
+    Turkic,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum StemmerArg {
+    English,
+    German,
+    Russian,
+    /// Also the closest one for Estonian
+    Finnish,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    #[arg(long)]
+    ignore_chars: Option<String>,
+    #[arg(long, value_enum)]
+    case: Option<CaseArg>,
+    /// Same as '--case sensitive'
+    #[arg(long, conflicts_with = "case")]
+    case_sensitive: bool,
+    #[arg(long)]
+    min_token_len: Option<usize>,
+    #[arg(long)]
+    normalize: bool,
+    #[arg(long)]
+    nfkc: bool,
+    #[arg(long)]
+    strip_diacritics: bool,
+    /// Fold styled letters and common Cyrillic, Greek, Armenian and Latin
+    /// lookalikes of ASCII letters, a curated list rather than the full
+    /// Unicode confusables data
+    #[arg(long)]
+    confusables: bool,
+    #[arg(long)]
+    leetspeak: bool,
+    #[arg(long, value_parser = parse_leet_pair, num_args=1.., value_delimiter = ',')]
+    leet_map: Option<Vec<(char, char)>>,
+    /// Match the inflected forms of the words by their stem
+    #[arg(long, value_enum)]
+    stemmer: Option<StemmerArg>,
+    /// Weights of the words as '<word>=<weight>', the others weigh 1
+    #[arg(long, value_parser = parse_weight, num_args=1.., value_delimiter = ',')]
+    weights: Vec<(String, f64)>,
+    /// Minimum total weight of the distinct words found in a message
+    #[arg(long)]
+    threshold: Option<f64>,
+}
+
+fn parse_weight(s: &str) -> Result<(String, f64), String> {
+    match s.rsplit_once('=') {
+        Some((word, weight)) if !word.is_empty() => weight
+            .parse()
+            .map(|weight| (word.to_owned(), weight))
+            .map_err(|e| format!("invalid weight '{}': {}", weight, e)),
+        _ => Err(format!("expected '<word>=<weight>', found '{}'", s)),
+    }
+}
+
+fn parse_leet_pair(s: &str) -> Result<(char, char), String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some(from), Some('='), Some(to), None) => Ok((from, to)),
+        _ => Err(format!("expected '<char>=<char>', found '{}'", s)),
+    }
+}
+
+impl From<ConfigArgs> for PatternConfig {
+    fn from(a: ConfigArgs) -> Self {
+        let leetspeak = match a.leet_map {
+            Some(m) => Some(m.into_iter().collect()),
+            None if a.leetspeak || a.normalize => Some(Normalization::default_leetspeak()),
+            None => None,
+        };
+        PatternConfig {
+            ignore_chars: a.ignore_chars.unwrap_or_default(),
+            case: match a.case {
+                _ if a.case_sensitive => CaseMode::Sensitive,
+                None => CaseMode::default(),
+                Some(CaseArg::Sensitive) => CaseMode::Sensitive,
+                Some(CaseArg::Simple) => CaseMode::Simple,
+                Some(CaseArg::Full) => CaseMode::Full,
+                Some(CaseArg::Turkic) => CaseMode::Turkic,
+            },
+            min_token_len: a.min_token_len,
+            normalization: Normalization {
+                nfkc: a.nfkc || a.normalize,
+                strip_diacritics: a.strip_diacritics || a.normalize,
+                confusables: a.confusables || a.normalize,
+                leetspeak,
+            },
+            stemmer: a.stemmer.map(|s| match s {
+                StemmerArg::English => Language::English,
+                StemmerArg::German => Language::German,
+                StemmerArg::Russian => Language::Russian,
+                StemmerArg::Finnish => Language::Finnish,
+            }),
+            weights: a.weights.into_iter().collect(),
+            threshold: a.threshold,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct ConditionArgs {
+    /// Only match messages of these authors
+    #[arg(long, num_args=1.., value_delimiter = ',', conflicts_with = "author_glob")]
+    author: Vec<String>,
+    /// Only match messages of the authors matching this glob, e.g. 'spam_*'
+    #[arg(long)]
+    author_glob: Option<String>,
+    /// Only match messages in these channels
+    #[arg(long, num_args=1.., value_delimiter = ',', conflicts_with = "channel_glob")]
+    channel: Vec<String>,
+    /// Only match messages in the channels matching this glob
+    #[arg(long)]
+    channel_glob: Option<String>,
+}
+
+fn name_condition(mut names: Vec<String>, glob: Option<String>) -> Option<NameCondition> {
+    match (names.len(), glob) {
+        (_, Some(g)) => Some(NameCondition::Glob(g)),
+        (0, None) => None,
+        (1, None) => names.pop().map(NameCondition::Exact),
+        (_, None) => Some(NameCondition::List(names)),
+    }
+}
+
+impl From<ConditionArgs> for Conditions {
+    fn from(a: ConditionArgs) -> Self {
+        Conditions {
+            author: name_condition(a.author, a.author_glob),
+            channel: name_condition(a.channel, a.channel_glob),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum AddCommand {
     Pattern {
         name: String,
-        #[arg(short, long, value_parser, num_args=1.., value_delimiter = ',')]
+        #[arg(short, long, value_parser, num_args=1.., value_delimiter = ',', conflicts_with_all = ["query", "file"])]
         words: Vec<String>,
+        #[arg(short, long, conflicts_with_all = ["mode", "file"])]
+        query: Option<String>,
+        /// Wordlist file with a term per line, reloaded when it changes
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+        /// Mark it as the default pattern, a label shown by `list` and `show`
+        /// which does not change the channels it applies to
         #[arg(short, long)]
         default: Option<bool>,
         #[arg(short, long, value_enum)]
-        mode: Option<MatchMode>,
+        mode: Option<ModeArg>,
+        #[arg(long, requires = "mode")]
+        max_distance: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[command(flatten)]
+        conditions: ConditionArgs,
+        /// Tokens whose hits are discarded, like 'scampi' for 'scam'
+        #[arg(short, long, value_parser, num_args=1.., value_delimiter = ',')]
+        except: Vec<String>,
     },
 }
 
@@ -32,8 +212,147 @@ enum GetCommand {
         author: Option<String>,
         #[arg(short, long)]
         channel: Option<String>,
+        /// Highlight the matched parts of the messages
+        #[arg(long)]
+        highlight: bool,
+        /// Only show the messages caught by this pattern
+        #[arg(short, long)]
+        pattern: Option<String>,
+        /// Sort the messages by their score, highest first
+        #[arg(long)]
+        by_score: bool,
     },
+    Patterns,
     Channels,
+    /// Print how often the patterns and each of their terms were hit
+    Stats {
+        #[arg(short, long)]
+        pattern: Option<String>,
+        /// Start counting again from zero once printed
+        #[arg(long)]
+        reset: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PatternCommand {
+    /// Print every pattern
+    List,
+    /// Print the pattern along with its settings
+    Show {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    /// Add words to a pattern made of words
+    AddWords {
+        name: String,
+        #[arg(required = true)]
+        words: Vec<String>,
+    },
+    /// Remove words from a pattern made of words
+    RemoveWords {
+        name: String,
+        #[arg(required = true)]
+        words: Vec<String>,
+    },
+    /// Discard the hits inside of these tokens
+    AddExceptions {
+        name: String,
+        #[arg(required = true)]
+        words: Vec<String>,
+    },
+    RemoveExceptions {
+        name: String,
+        #[arg(required = true)]
+        words: Vec<String>,
+    },
+    /// Mark the pattern as the default one. This is only a label shown by
+    /// `list` and `show`, matching is decided by the bindings: channels
+    /// without any get the patterns bound to '*', or else every unbound one.
+    SetDefault {
+        name: String,
+    },
+    /// Print the pattern as JSON
+    Export {
+        name: String,
+    },
+    /// Add a pattern from a file written by `export`
+    Import {
+        file: PathBuf,
+        /// Use this name instead of the one in the file
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Mark it as the default pattern, see `set-default`
+        #[arg(short, long)]
+        default: bool,
+    },
+    /// Check messages against the pattern
+    Enable {
+        name: String,
+    },
+    /// Stop checking messages against the pattern
+    Disable {
+        name: String,
+    },
+    /// Apply the pattern to the channels, '*' stands for every channel
+    /// without patterns of its own
+    Bind {
+        name: String,
+        #[arg(required = true)]
+        channels: Vec<String>,
+    },
+    /// Stop applying the pattern to the channels, or to any when none are given
+    Unbind {
+        name: String,
+        channels: Vec<String>,
+    },
+    /// Print every revision of the pattern with what it changed
+    Revisions {
+        name: String,
+    },
+    /// Print what changed between two revisions of the pattern
+    Diff {
+        name: String,
+        from: u64,
+        to: u64,
+    },
+    /// Restore the pattern as it was at the revision
+    Rollback {
+        name: String,
+        revision: u64,
+    },
+    /// Count what a pattern would have caught in the stored messages,
+    /// without storing anything
+    Test {
+        /// Name of the pattern, leave it out to test `--words` instead
+        #[arg(required_unless_present = "words")]
+        name: Option<String>,
+        #[arg(short, long, value_parser, num_args=1.., value_delimiter = ',', conflicts_with = "name")]
+        words: Vec<String>,
+        #[arg(short, long, value_enum, requires = "words")]
+        mode: Option<ModeArg>,
+        #[arg(long, requires = "mode")]
+        max_distance: Option<usize>,
+        #[arg(short, long)]
+        channel: Option<String>,
+        #[arg(short, long)]
+        author: Option<String>,
+        /// Only check messages stored since then, e.g. '2024-05-01 18:00:00'
+        #[arg(short, long, conflicts_with = "log")]
+        since: Option<String>,
+        /// Check the raw IRC lines of a log file instead
+        #[arg(short, long)]
+        log: Option<PathBuf>,
+        /// How many of the caught messages to print
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -50,12 +369,16 @@ enum CliCommand {
     },
     Add {
         #[command(subcommand)]
-        add_command: AddCommand,
+        add_command: Box<AddCommand>,
     },
     Get {
         #[command(subcommand)]
         get_command: GetCommand,
     },
+    Pattern {
+        #[command(subcommand)]
+        pattern_command: PatternCommand,
+    },
 }
 
 #[inline]
@@ -82,40 +405,169 @@ fn parse_join(channels: Vec<String>) -> Action {
 }
 
 #[inline]
-fn parse_add(a: AddCommand) -> Action {
-    match a {
+fn parse_mode(mode: Option<ModeArg>, max_distance: Option<usize>) -> MatchMode {
+    match mode {
+        None => MatchMode::default(),
+        Some(ModeArg::Inclusive) => MatchMode::Inclusive,
+        Some(ModeArg::Exclusive) => MatchMode::Exclusive,
+        Some(ModeArg::Regex) => MatchMode::Regex,
+        Some(ModeArg::Fuzzy) => MatchMode::Fuzzy {
+            max_distance: max_distance.unwrap_or(1),
+        },
+        Some(ModeArg::Prefix) => MatchMode::Prefix,
+        Some(ModeArg::Suffix) => MatchMode::Suffix,
+        Some(ModeArg::Glob) => MatchMode::Glob,
+    }
+}
+
+#[inline]
+fn parse_add(a: AddCommand) -> IoResult<Action> {
+    let a = match a {
         AddCommand::Pattern {
             name,
             words,
+            query,
+            file,
             default,
             mode,
-        } => Action::Add(AddAction::Pattern {
-            raw_pattern: (words, mode.unwrap_or_default()),
+            max_distance,
+            config,
+            conditions,
+            except,
+        } => AddAction::Pattern {
+            raw_pattern: match (query, file) {
+                (Some(q), _) => RawPattern::Query(q),
+                // The daemon has a working directory of its own
+                (_, Some(f)) => {
+                    RawPattern::File(std::path::absolute(f)?, parse_mode(mode, max_distance))
+                }
+                _ => RawPattern::Words(words, parse_mode(mode, max_distance)),
+            },
             name,
+            config: Box::new(config.into()),
+            conditions: conditions.into(),
+            exceptions: except,
             default: default.unwrap_or_default(),
-        }),
-    }
+        },
+    };
+    Ok(Action::Add(a))
 }
 
 fn parse_get(a: GetCommand) -> Action {
     match a {
-        GetCommand::Messages { author, channel } => {
-            Action::Get(GetAction::Messages { author, channel })
-        }
+        GetCommand::Messages {
+            author,
+            channel,
+            pattern,
+            by_score,
+            ..
+        } => Action::Get(GetAction::Messages {
+            author,
+            channel,
+            pattern,
+            by_score,
+        }),
+        GetCommand::Patterns => Action::Get(GetAction::Patterns),
         GetCommand::Channels => Action::Get(GetAction::Channels),
+        GetCommand::Stats { pattern, reset } => Action::Get(GetAction::Stats { pattern, reset }),
     }
 }
 
+fn parse_pattern(a: PatternCommand) -> IoResult<Action> {
+    let a = match a {
+        PatternCommand::List => PatternAction::List,
+        PatternCommand::Show { name } => PatternAction::Show { name },
+        PatternCommand::Remove { name } => PatternAction::Remove { name },
+        PatternCommand::Rename { name, new_name } => PatternAction::Rename { name, new_name },
+        PatternCommand::AddWords { name, words } => PatternAction::AddWords { name, words },
+        PatternCommand::RemoveWords { name, words } => PatternAction::RemoveWords { name, words },
+        PatternCommand::AddExceptions { name, words } => {
+            PatternAction::AddExceptions { name, words }
+        }
+        PatternCommand::RemoveExceptions { name, words } => {
+            PatternAction::RemoveExceptions { name, words }
+        }
+        PatternCommand::SetDefault { name } => PatternAction::SetDefault { name },
+        PatternCommand::Export { name } => PatternAction::Export { name },
+        PatternCommand::Import {
+            file,
+            name,
+            default,
+        } => {
+            let mut pattern =
+                serde_json::from_str::<ExportedPattern>(&std::fs::read_to_string(file)?)?;
+            if let Some(name) = name {
+                pattern.name = name;
+            }
+            PatternAction::Import {
+                pattern: Box::new(pattern),
+                default,
+            }
+        }
+        PatternCommand::Enable { name } => PatternAction::Enable { name },
+        PatternCommand::Disable { name } => PatternAction::Disable { name },
+        PatternCommand::Bind { name, channels } => PatternAction::Bind { name, channels },
+        PatternCommand::Unbind { name, channels } => PatternAction::Unbind { name, channels },
+        PatternCommand::Revisions { name } => PatternAction::Revisions { name },
+        PatternCommand::Diff { name, from, to } => PatternAction::Diff { name, from, to },
+        PatternCommand::Rollback { name, revision } => PatternAction::Rollback { name, revision },
+        PatternCommand::Test {
+            name,
+            words,
+            mode,
+            max_distance,
+            channel,
+            author,
+            since,
+            log,
+            samples,
+        } => {
+            let target = match name {
+                Some(name) => BacktestTarget::Pattern(name),
+                None => BacktestTarget::Words(words, parse_mode(mode, max_distance)),
+            };
+            return Ok(Action::Backtest(BacktestAction {
+                target,
+                // The daemon has a working directory of its own
+                log: log.map(std::path::absolute).transpose()?,
+                channel,
+                author,
+                since,
+                samples,
+            }));
+        }
+    };
+    Ok(Action::Pattern(a))
+}
+
 #[tokio::main]
 async fn main() -> IoResult<()> {
     let args = Args::parse();
+    let highlight = matches!(
+        args.command,
+        CliCommand::Get {
+            get_command: GetCommand::Messages {
+                highlight: true,
+                ..
+            }
+        }
+    );
+    // Exported patterns are printed as they are, so they can be redirected
+    // to a file
+    let raw = matches!(
+        args.command,
+        CliCommand::Pattern {
+            pattern_command: PatternCommand::Export { .. }
+        }
+    );
 
     let action = match args.command {
         CliCommand::Start { channels } => parse_start(channels),
         CliCommand::Part { channels } => parse_part(channels),
         CliCommand::Join { channels } => parse_join(channels),
-        CliCommand::Add { add_command } => parse_add(add_command),
+        CliCommand::Add { add_command } => parse_add(*add_command)?,
         CliCommand::Get { get_command } => parse_get(get_command),
+        CliCommand::Pattern { pattern_command } => parse_pattern(pattern_command)?,
     };
 
     let res = execute_action(action).await?;
@@ -136,6 +588,11 @@ async fn main() -> IoResult<()> {
             }
         },
         ActionRes::Success => println!("ok"),
+        ActionRes::Data(s) if raw => println!("{}", s),
+        ActionRes::Data(s) if highlight => match serde_json::from_str(&s) {
+            Ok(messages) => print_highlighted(messages),
+            Err(e) => eprintln!("failed: malformed messages: {}", e),
+        },
         ActionRes::Data(s) => {
             println!("ok; received data:");
             println!("{}", s);
@@ -145,6 +602,44 @@ async fn main() -> IoResult<()> {
     Ok(())
 }
 
+fn print_highlighted(messages: Vec<TwitchMessage>) {
+    for m in messages {
+        let mut spans = m
+            .report
+            .iter()
+            .flat_map(|r| &r.hits)
+            .map(|h| (h.start, h.end))
+            .filter(|&(start, end)| m.message.get(start..end).is_some())
+            .collect::<Vec<_>>();
+        spans.sort_unstable();
+
+        let mut line = String::with_capacity(m.message.len());
+        let mut last = 0;
+        for (start, end) in spans {
+            // Overlapping hits are merged into a single highlighted span
+            if end <= last {
+                continue;
+            }
+            let start = start.max(last);
+            line.push_str(&m.message[last..start]);
+            line.push_str("\x1b[1;31m");
+            line.push_str(&m.message[start..end]);
+            line.push_str("\x1b[0m");
+            last = end;
+        }
+        line.push_str(&m.message[last..]);
+
+        let mut header = format!("[{}]", m.time);
+        if let Some(pattern) = &m.pattern {
+            header.push_str(&format!(" {}", pattern));
+        }
+        if let Some(score) = m.score {
+            header.push_str(&format!(" ({})", score));
+        }
+        println!("{} #{} {}: {}", header, m.channel, m.author, line);
+    }
+}
+
 #[inline]
 async fn execute_action(action: Action) -> IoResult<ActionRes> {
     let mut us = UnixStream::connect(SOCKET_PATH).await?;
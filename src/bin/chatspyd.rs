@@ -1,6 +1,8 @@
-use chatspy::match_pattern::MatchPattern;
+use chatspy::backtest::Backtest;
+use chatspy::match_pattern::conditions::{Conditions, NameCondition};
+use chatspy::match_pattern::{MatchPattern, PatternDef};
 use chatspy::protocol::*;
-use chatspy::storage::{get_messages, insert_message, run_init_migration};
+use chatspy::storage::{for_each_message, get_messages, insert_message, run_init_migration};
 use chatspy::twitch::{parse_privmsg, spawn_twitch_irc, TwitchCmd, TwitchCmdType, TwitchInfoCmd};
 use chatspy::{
     AppEvent, AppEventEmitter, PatternStorage, TwitchEvent, SOCKET_PATH, TWITCH_DB_PATH,
@@ -8,8 +10,9 @@ use chatspy::{
 use clap::Parser;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
+use tokio::net::{UnixListener, UnixStream};
 
 // fn twitch_auth_uri(port: u16) -> String {
 //     format!(
@@ -89,6 +92,8 @@ const TEST_CHANNELS: [&str; 33] = [
     "tarik",
 ];
 
+const WORDLIST_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 #[derive(Parser)]
 struct Args {
     #[arg(short, long, value_parser, num_args=1.., value_delimiter = ',')]
@@ -97,9 +102,10 @@ struct Args {
     test: Option<bool>,
 }
 
-fn open_sqlite() {
+fn open_sqlite() -> rusqlite::Connection {
     let sqlt = rusqlite::Connection::open(TWITCH_DB_PATH).unwrap();
     run_init_migration(&sqlt);
+    sqlt
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -112,13 +118,17 @@ async fn main() -> std::io::Result<()> {
         args.channels
     };
 
-    open_sqlite();
-    let pattern_storage = Arc::new(PatternStorage::new());
+    let (pattern_storage, errors) = PatternStorage::open(open_sqlite());
+    for (name, e) in errors {
+        eprintln!("failed to load pattern '{}': {}", name, e);
+    }
+    let pattern_storage = Arc::new(pattern_storage);
     let (event_emitter, event_receiver) = crossbeam::channel::bounded(128);
 
     spawn_socket(event_emitter.clone())?;
     let twitch_cmd_sender = spawn_twitch_irc(event_emitter.clone(), prejoin);
     let processor_sender = spawn_processor(pattern_storage.clone());
+    spawn_wordlist_watcher(pattern_storage.clone());
 
     let (kill_tx, kill_rx) = tokio::sync::oneshot::channel();
     let kill_tx = Arc::new(Mutex::new(Some(kill_tx)));
@@ -132,7 +142,11 @@ async fn main() -> std::io::Result<()> {
                         let _ = processor_sender.send(m);
                     }
                 },
-                AppEvent::ExternalAction { action, responder } => match action {
+                AppEvent::ExternalAction {
+                    action,
+                    client,
+                    responder,
+                } => match action {
                     Action::Twitch(action) => {
                         let _ = twitch_cmd_sender.blocking_send(TwitchCmd {
                             action: TwitchCmdType::Connection(action),
@@ -143,21 +157,57 @@ async fn main() -> std::io::Result<()> {
                         AddAction::Pattern {
                             name,
                             raw_pattern: rp,
+                            config,
+                            conditions,
+                            exceptions,
                             default,
                         } => {
                             let pattern_storage = pattern_storage.clone();
                             tokio::task::block_in_place(move || {
-                                let p = MatchPattern::builder().words(rp.0).mode(rp.1).build();
-                                let _ = pattern_storage.add(name, p, default);
-                                let _ = responder.send(ActionRes::Success);
+                                let p = match rp {
+                                    RawPattern::Words(words, mode) => MatchPattern::builder()
+                                        .config(*config)
+                                        .mode(mode)
+                                        .words(words)
+                                        .try_build(),
+                                    RawPattern::Query(q) => MatchPattern::from_query(&q, *config),
+                                    RawPattern::File(path, mode) => {
+                                        MatchPattern::from_file(path, mode, *config)
+                                    }
+                                };
+                                let p = p.map(|mut p| {
+                                    p.set_conditions(conditions);
+                                    p.set_exceptions(exceptions);
+                                    p
+                                });
+                                let res = match p {
+                                    Ok(p) => pattern_storage
+                                        .add(name.clone(), p, default, &client)
+                                        .map(|_| ActionRes::Success)
+                                        .map_err(|_| Error::PatternExists { name }),
+                                    Err(e) => Err(Error::InvalidPattern {
+                                        name,
+                                        reason: e.to_string(),
+                                    }),
+                                };
+                                let res = res.unwrap_or_else(|e| ActionRes::Failure {
+                                    errors: vec![e],
+                                    level: FailureLevel::Critical,
+                                });
+                                let _ = responder.send(res);
                             });
                         }
                     },
                     Action::Get(a) => match a {
-                        GetAction::Messages { channel, author } => {
+                        GetAction::Messages {
+                            channel,
+                            author,
+                            pattern,
+                            by_score,
+                        } => {
                             tokio::task::block_in_place(move || {
                                 let sqlt = rusqlite::Connection::open(TWITCH_DB_PATH).unwrap();
-                                let vm = get_messages(&sqlt, author, channel);
+                                let vm = get_messages(&sqlt, author, channel, pattern, by_score);
                                 let res = serde_json::to_string_pretty(&vm).unwrap();
                                 let _ = responder.send(ActionRes::Data(res));
                             });
@@ -168,8 +218,27 @@ async fn main() -> std::io::Result<()> {
                                 responder,
                             });
                         }
-                        GetAction::Patterns => {}
+                        GetAction::Stats { pattern, reset } => {
+                            let res = pattern_storage
+                                .stats(pattern.as_deref(), reset)
+                                .map(|s| ActionRes::Data(serde_json::to_string_pretty(&s).unwrap()))
+                                .unwrap_or_else(|e| ActionRes::Failure {
+                                    errors: vec![e],
+                                    level: FailureLevel::Critical,
+                                });
+                            let _ = responder.send(res);
+                        }
+                        GetAction::Patterns => {
+                            let res =
+                                serde_json::to_string_pretty(&pattern_storage.list()).unwrap();
+                            let _ = responder.send(ActionRes::Data(res));
+                        }
                     },
+                    Action::Pattern(a) => {
+                        let res = handle_pattern_action(&pattern_storage, a, &client);
+                        let _ = responder.send(res);
+                    }
+                    Action::Backtest(a) => spawn_backtest(pattern_storage.clone(), a, responder),
                     Action::Kill => {
                         let _ = kill_tx.clone().lock().unwrap().take().unwrap().send(());
                     }
@@ -187,6 +256,192 @@ async fn main() -> std::io::Result<()> {
     Ok(())
 }
 
+fn handle_pattern_action(
+    pattern_storage: &PatternStorage,
+    a: PatternAction,
+    client: &str,
+) -> ActionRes {
+    let res = match a {
+        PatternAction::List => Ok(ActionRes::Data(
+            serde_json::to_string_pretty(&pattern_storage.list()).unwrap(),
+        )),
+        PatternAction::Show { name } => match pattern_storage.info(&name) {
+            Some(info) => Ok(ActionRes::Data(
+                serde_json::to_string_pretty(&info).unwrap(),
+            )),
+            None => Err(Error::UnknownPattern { name }),
+        },
+        PatternAction::Export { name } => match pattern_storage.get(&name) {
+            Some(p) => {
+                let pattern = PatternDef::from(&*p.read().unwrap());
+                let exported = ExportedPattern { name, pattern };
+                Ok(ActionRes::Data(
+                    serde_json::to_string_pretty(&exported).unwrap(),
+                ))
+            }
+            None => Err(Error::UnknownPattern { name }),
+        },
+        // The definition is rebuilt like a new pattern, so nothing from the
+        // file is used without validation
+        PatternAction::Import { pattern, default } => {
+            let ExportedPattern { name, pattern } = *pattern;
+            match MatchPattern::try_from(pattern) {
+                Ok(p) => pattern_storage
+                    .add(name.clone(), p, default, client)
+                    .map(|_| ActionRes::Success)
+                    .map_err(|_| Error::PatternExists { name }),
+                Err(e) => Err(Error::InvalidPattern {
+                    name,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        PatternAction::Remove { name } => found(pattern_storage.remove(&name), name),
+        PatternAction::Rename { name, new_name } => pattern_storage
+            .rename(&name, new_name)
+            .map(|_| ActionRes::Success),
+        PatternAction::SetDefault { name } => found(pattern_storage.set_default(&name), name),
+        PatternAction::Enable { name } => found(pattern_storage.set_enabled(&name, true), name),
+        PatternAction::Disable { name } => found(pattern_storage.set_enabled(&name, false), name),
+        PatternAction::Bind { name, channels } => {
+            found(pattern_storage.bind(&name, &channels), name)
+        }
+        PatternAction::Unbind { name, channels } => {
+            found(pattern_storage.unbind(&name, &channels), name)
+        }
+        PatternAction::AddWords { name, words } => pattern_storage
+            .add_words(&name, words, client)
+            .map(|_| ActionRes::Success),
+        // Words the pattern did not have are reported without failing the rest
+        PatternAction::RemoveWords { name, words } => pattern_storage
+            .remove_words(&name, &words, client)
+            .map(|missing| {
+                uncritical(
+                    missing
+                        .into_iter()
+                        .map(|word| Error::UnknownWord {
+                            name: name.clone(),
+                            word,
+                        })
+                        .collect(),
+                )
+            }),
+        PatternAction::AddExceptions { name, words } => pattern_storage
+            .add_exceptions(&name, words, client)
+            .map(|_| ActionRes::Success),
+        PatternAction::RemoveExceptions { name, words } => pattern_storage
+            .remove_exceptions(&name, &words, client)
+            .map(|missing| {
+                uncritical(
+                    missing
+                        .into_iter()
+                        .map(|word| Error::UnknownException {
+                            name: name.clone(),
+                            word,
+                        })
+                        .collect(),
+                )
+            }),
+        PatternAction::Revisions { name } => pattern_storage
+            .revisions(&name)
+            .map(|r| ActionRes::Data(serde_json::to_string_pretty(&r).unwrap())),
+        PatternAction::Diff { name, from, to } => pattern_storage
+            .diff(&name, from, to)
+            .map(|d| ActionRes::Data(serde_json::to_string_pretty(&d).unwrap())),
+        PatternAction::Rollback { name, revision } => pattern_storage
+            .rollback(&name, revision, client)
+            .map(|_| ActionRes::Success),
+    };
+
+    res.unwrap_or_else(|e| ActionRes::Failure {
+        errors: vec![e],
+        level: FailureLevel::Critical,
+    })
+}
+
+fn uncritical(errors: Vec<Error>) -> ActionRes {
+    if errors.is_empty() {
+        ActionRes::Success
+    } else {
+        ActionRes::Failure {
+            errors,
+            level: FailureLevel::Uncritical,
+        }
+    }
+}
+
+fn found(found: bool, name: String) -> Result<ActionRes, Error> {
+    if found {
+        Ok(ActionRes::Success)
+    } else {
+        Err(Error::UnknownPattern { name })
+    }
+}
+
+// A backtest over a large table runs on a thread of its own, so the events
+// behind it and the live capture are not held up
+fn spawn_backtest(
+    pattern_storage: Arc<PatternStorage>,
+    a: BacktestAction,
+    responder: tokio::sync::oneshot::Sender<ActionRes>,
+) {
+    let _ = std::thread::spawn(move || {
+        let res = match run_backtest(&pattern_storage, a) {
+            Ok(report) => ActionRes::Data(serde_json::to_string_pretty(&report).unwrap()),
+            Err(e) => ActionRes::Failure {
+                errors: vec![e],
+                level: FailureLevel::Critical,
+            },
+        };
+        let _ = responder.send(res);
+    });
+}
+
+fn run_backtest(
+    pattern_storage: &PatternStorage,
+    a: BacktestAction,
+) -> Result<BacktestReport, Error> {
+    // Named patterns are copied, so edits to them are not blocked meanwhile
+    let pattern = match a.target {
+        BacktestTarget::Pattern(name) => match pattern_storage.get(&name) {
+            Some(p) => p.read().unwrap().clone(),
+            None => return Err(Error::UnknownPattern { name }),
+        },
+        BacktestTarget::Words(words, mode) => MatchPattern::builder()
+            .mode(mode)
+            .words(words)
+            .try_build()
+            .map_err(|e| Error::InvalidPattern {
+                name: "<inline>".to_owned(),
+                reason: e.to_string(),
+            })?,
+    };
+    let filter = Conditions {
+        author: a.author.map(NameCondition::Exact),
+        channel: a.channel.map(NameCondition::Exact),
+    };
+
+    let mut backtest = Backtest::new(&pattern, filter, a.samples);
+    match a.log {
+        Some(path) => {
+            let log = std::fs::read_to_string(&path).map_err(|e| Error::LogUnreadable {
+                path: path.clone(),
+                reason: e.to_string(),
+            })?;
+            for msg in log.lines().filter_map(parse_privmsg) {
+                backtest.check(&msg, None);
+            }
+        }
+        None => {
+            let sqlt = rusqlite::Connection::open(TWITCH_DB_PATH).unwrap();
+            for_each_message(&sqlt, a.since.as_deref(), |msg, time| {
+                backtest.check(&msg, Some(time))
+            });
+        }
+    }
+    Ok(backtest.finish())
+}
+
 fn spawn_processor(pattern_storage: Arc<PatternStorage>) -> crossbeam::channel::Sender<String> {
     let (msg_sender, msg_receiver) = crossbeam::channel::bounded::<String>(64);
     let _ = std::thread::spawn(move || {
@@ -196,45 +451,91 @@ fn spawn_processor(pattern_storage: Arc<PatternStorage>) -> crossbeam::channel::
             .unwrap();
 
         for msg in msg_receiver {
-            let lock = pattern_storage.default_pattern().read().unwrap();
-
-            if let Some(p) = lock.clone() {
-                pool.spawn(move || {
-                    if let Some(privmsg) = parse_privmsg(&msg) {
-                        if p.read().unwrap().match_str(&privmsg.message) {
-                            let sqlt = rusqlite::Connection::open(TWITCH_DB_PATH).unwrap();
-                            insert_message(&sqlt, privmsg);
-                        }
-                    }
-                })
+            if pattern_storage.enabled_patterns().is_empty() {
+                continue;
             }
+
+            let pattern_storage = pattern_storage.clone();
+            pool.spawn(move || {
+                let Some(privmsg) = parse_privmsg(&msg) else {
+                    return;
+                };
+                let mut sqlt = None;
+                for (name, p) in pattern_storage.patterns_for(&privmsg.channel) {
+                    let report = p.read().unwrap().match_message(&privmsg);
+                    if let Some(report) = report {
+                        let sqlt = sqlt.get_or_insert_with(|| {
+                            rusqlite::Connection::open(TWITCH_DB_PATH).unwrap()
+                        });
+                        insert_message(sqlt, &privmsg, &name, &report);
+                        pattern_storage.record_hit(&name, &privmsg.channel, &report);
+                    }
+                }
+            })
         }
     });
     msg_sender
 }
 
+fn spawn_wordlist_watcher(pattern_storage: Arc<PatternStorage>) {
+    let _ = std::thread::spawn(move || loop {
+        std::thread::sleep(WORDLIST_POLL_INTERVAL);
+        for (name, e) in pattern_storage.reload_wordlists() {
+            eprintln!("failed to reload pattern '{}': {}", name, e);
+        }
+    });
+}
+
 fn spawn_socket(emitter: AppEventEmitter) -> std::io::Result<()> {
     close_socket()?;
     let _ = tokio::spawn(async move {
         let ul = UnixListener::bind(SOCKET_PATH)?;
-        while let Ok((mut stream, _)) = ul.accept().await {
-            let mut buf = vec![];
-
-            let _ = stream.read_to_end(&mut buf).await?;
-            let action = serde_json::from_slice::<Action>(&buf)?;
-
-            let (responder, res_receiver) = tokio::sync::oneshot::channel();
-            let _ = emitter.send(AppEvent::ExternalAction { action, responder });
-            let res = res_receiver.await.unwrap();
-
-            stream.write_all(&serde_json::to_vec(&res)?).await?;
-            stream.shutdown().await?;
+        // Every connection waits for its response on its own, so a slow
+        // command does not hold up the others
+        while let Ok((stream, _)) = ul.accept().await {
+            tokio::spawn(handle_connection(stream, emitter.clone()));
         }
         Ok::<(), std::io::Error>(())
     });
     Ok(())
 }
 
+async fn handle_connection(
+    mut stream: UnixStream,
+    emitter: AppEventEmitter,
+) -> std::io::Result<()> {
+    let mut buf = vec![];
+
+    let _ = stream.read_to_end(&mut buf).await?;
+    let action = serde_json::from_slice::<Action>(&buf)?;
+    let client = client_name(&stream);
+
+    let (responder, res_receiver) = tokio::sync::oneshot::channel();
+    let _ = emitter.send(AppEvent::ExternalAction {
+        action,
+        client,
+        responder,
+    });
+    // The handler is gone if it panicked, the client only gets an EOF
+    let Ok(res) = res_receiver.await else {
+        return stream.shutdown().await;
+    };
+
+    stream.write_all(&serde_json::to_vec(&res)?).await?;
+    stream.shutdown().await
+}
+
+// Clients are told apart by the credentials of the process on the other end
+fn client_name(stream: &UnixStream) -> String {
+    match stream.peer_cred() {
+        Ok(cred) => match cred.pid() {
+            Some(pid) => format!("uid {} pid {}", cred.uid(), pid),
+            None => format!("uid {}", cred.uid()),
+        },
+        Err(_) => "unknown".to_owned(),
+    }
+}
+
 fn close_socket() -> std::io::Result<()> {
     let socket = Path::new(SOCKET_PATH);
     if socket.exists() {
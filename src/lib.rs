@@ -1,18 +1,28 @@
 #![feature(lazy_cell)]
 
-use crate::match_pattern::MatchPattern;
-use crate::protocol::{Action, ActionRes};
+use crate::match_pattern::{MatchPattern, PatternDef, PatternError};
+use crate::match_pattern::{MatchReport, PatternDiff};
+use crate::protocol::{
+    Action, ActionRes, Error, PatternInfo, PatternStats, RawPattern, RevisionInfo,
+};
+use crate::stats::Stats;
+use crate::storage::StoredPattern;
 use fnv::FnvHashMap;
-use std::sync::{Arc, RwLock};
+use rusqlite::Connection;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, RwLock};
 
+pub mod backtest;
 pub mod match_pattern;
 pub mod network;
 pub mod protocol;
+pub mod stats;
 pub mod storage;
 pub mod twitch;
 
 pub const SOCKET_PATH: &str = "/tmp/chatspy.socket";
 pub const TWITCH_DB_PATH: &str = "./twitch_storage.sqlite";
+pub const ANY_CHANNEL: &str = "*";
 
 #[derive(Debug)]
 pub enum TwitchEvent {
@@ -24,6 +34,7 @@ pub enum AppEvent {
     Twitch(TwitchEvent),
     ExternalAction {
         action: Action,
+        client: String,
         responder: tokio::sync::oneshot::Sender<ActionRes>,
     },
     Error,
@@ -34,37 +45,554 @@ pub type AppEventEmitter = crossbeam::channel::Sender<AppEvent>;
 
 type Locked<T> = Arc<RwLock<T>>;
 
+#[derive(Debug)]
+struct PatternEntry {
+    pattern: LockedPattern,
+    enabled: bool,
+}
+
+fn normalize_channel(channel: &str) -> String {
+    channel.trim_start_matches('#').to_ascii_lowercase()
+}
+
+// `enabled` is a snapshot of the enabled patterns sorted by name, rebuilt on
+// every change so the processor only clones an `Arc` per message.
+// `bindings` maps channels to the names of the patterns applied to them,
+// `unbound` is the snapshot of the enabled patterns bound to none of them.
+// With a database every change is written through to it, and every change of
+// a definition is kept there as a revision of the pattern.
 #[derive(Debug)]
 pub struct PatternStorage {
-    patterns: RwLock<FnvHashMap<String, LockedPattern>>,
-    active_pattern: RwLock<Option<LockedPattern>>,
+    patterns: RwLock<FnvHashMap<String, PatternEntry>>,
+    enabled: RwLock<Arc<[(String, LockedPattern)]>>,
+    bindings: RwLock<FnvHashMap<String, BTreeSet<String>>>,
+    unbound: RwLock<Arc<[(String, LockedPattern)]>>,
+    default_pattern: RwLock<Option<String>>,
+    stats: Stats,
+    // Number of edits and rollbacks, held from the copy of a pattern to its
+    // swap so that none of them overwrites another one. Wordlist reloads are
+    // built without it and dropped when an edit came in meanwhile.
+    edits: Mutex<u64>,
+    db: Option<Mutex<Connection>>,
 }
 
 impl PatternStorage {
     pub fn new() -> Self {
         PatternStorage {
             patterns: RwLock::new(FnvHashMap::default()),
-            active_pattern: RwLock::new(None),
+            enabled: RwLock::new(Arc::new([])),
+            unbound: RwLock::new(Arc::new([])),
+            bindings: RwLock::new(FnvHashMap::default()),
+            default_pattern: RwLock::new(None),
+            stats: Stats::default(),
+            edits: Mutex::new(0),
+            db: None,
         }
     }
 
-    pub fn add(&self, n: String, p: MatchPattern, new_default: bool) -> Result<(), ()> {
+    // Loads the patterns stored in the database, the ones which can't be
+    // rebuilt anymore are kept there and returned with the reason
+    pub fn open(conn: Connection) -> (Self, Vec<(String, PatternError)>) {
+        let mut patterns = FnvHashMap::default();
+        let mut default_pattern = None;
+        let mut errors = vec![];
+        for p in storage::get_patterns(&conn) {
+            let p = match p {
+                Ok(p) => p,
+                Err((name, reason)) => {
+                    errors.push((name, PatternError::MalformedDefinition(reason)));
+                    continue;
+                }
+            };
+            match MatchPattern::try_from(p.definition) {
+                Ok(pattern) => {
+                    if p.default {
+                        default_pattern = Some(p.name.clone());
+                    }
+                    let entry = PatternEntry {
+                        pattern: Arc::new(RwLock::new(pattern)),
+                        enabled: p.enabled,
+                    };
+                    patterns.insert(p.name, entry);
+                }
+                Err(e) => errors.push((p.name, e)),
+            }
+        }
+
+        let mut bindings = FnvHashMap::<_, BTreeSet<_>>::default();
+        for (ch, n) in storage::get_bindings(&conn) {
+            bindings.entry(ch).or_default().insert(n);
+        }
+
+        let storage = PatternStorage {
+            patterns: RwLock::new(FnvHashMap::default()),
+            enabled: RwLock::new(Arc::new([])),
+            unbound: RwLock::new(Arc::new([])),
+            bindings: RwLock::new(bindings),
+            default_pattern: RwLock::new(default_pattern),
+            stats: Stats::default(),
+            edits: Mutex::new(0),
+            db: Some(Mutex::new(conn)),
+        };
+        storage.update_enabled(&patterns);
+        *storage.patterns.write().unwrap() = patterns;
+        (storage, errors)
+    }
+
+    fn with_db(&self, f: impl FnOnce(&Connection)) {
+        if let Some(db) = &self.db {
+            f(&db.lock().unwrap());
+        }
+    }
+
+    pub fn add(
+        &self,
+        n: String,
+        p: MatchPattern,
+        new_default: bool,
+        client: &str,
+    ) -> Result<(), ()> {
         let mut patterns_lock = self.patterns.write().unwrap();
         if !patterns_lock.contains_key(&n) {
-            let p = Arc::new(RwLock::new(p));
-            let mut default_lock = self.active_pattern.write().unwrap();
-            if default_lock.is_none() || new_default {
-                *default_lock = Some(p.clone());
+            let mut default_lock = self.default_pattern.write().unwrap();
+            let default = default_lock.is_none() || new_default;
+            if default {
+                *default_lock = Some(n.clone());
             }
-            patterns_lock.insert(n, p);
+            self.with_db(|conn| {
+                if default {
+                    storage::update_default_pattern(conn, None);
+                }
+                let stored = StoredPattern {
+                    name: n.clone(),
+                    definition: PatternDef::from(&p),
+                    default,
+                    enabled: true,
+                };
+                storage::insert_pattern(conn, &stored);
+                storage::insert_revision(conn, &n, client, &stored.definition);
+            });
+
+            let entry = PatternEntry {
+                pattern: Arc::new(RwLock::new(p)),
+                enabled: true,
+            };
+            patterns_lock.insert(n, entry);
+            self.update_enabled(&patterns_lock);
             Ok(())
         } else {
             Err(())
         }
     }
 
-    pub fn default_pattern(&self) -> &RwLock<Option<LockedPattern>> {
-        &self.active_pattern
+    pub fn get(&self, n: &str) -> Option<LockedPattern> {
+        self.patterns
+            .read()
+            .unwrap()
+            .get(n)
+            .map(|e| e.pattern.clone())
+    }
+
+    pub fn set_enabled(&self, n: &str, enabled: bool) -> bool {
+        let mut patterns_lock = self.patterns.write().unwrap();
+        match patterns_lock.get_mut(n) {
+            Some(e) => {
+                e.enabled = enabled;
+                self.update_enabled(&patterns_lock);
+                self.with_db(|conn| storage::update_pattern_enabled(conn, n, enabled));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&self, n: &str) -> bool {
+        let mut patterns_lock = self.patterns.write().unwrap();
+        if patterns_lock.remove(n).is_none() {
+            return false;
+        }
+        self.bindings.write().unwrap().retain(|_, names| {
+            names.remove(n);
+            !names.is_empty()
+        });
+        self.update_enabled(&patterns_lock);
+        self.with_db(|conn| storage::delete_pattern(conn, n));
+        self.stats.remove(n);
+
+        let mut default_lock = self.default_pattern.write().unwrap();
+        if default_lock.as_deref() == Some(n) {
+            *default_lock = None;
+        }
+        true
+    }
+
+    pub fn rename(&self, n: &str, new_n: String) -> Result<(), Error> {
+        let mut patterns_lock = self.patterns.write().unwrap();
+        if patterns_lock.contains_key(&new_n) {
+            return Err(Error::PatternExists { name: new_n });
+        }
+        let Some(entry) = patterns_lock.remove(n) else {
+            return Err(Error::UnknownPattern { name: n.to_owned() });
+        };
+        patterns_lock.insert(new_n.clone(), entry);
+        for names in self.bindings.write().unwrap().values_mut() {
+            if names.remove(n) {
+                names.insert(new_n.clone());
+            }
+        }
+        self.update_enabled(&patterns_lock);
+        self.with_db(|conn| storage::rename_pattern(conn, n, &new_n));
+        self.stats.rename(n, &new_n);
+
+        let mut default_lock = self.default_pattern.write().unwrap();
+        if default_lock.as_deref() == Some(n) {
+            *default_lock = Some(new_n);
+        }
+        Ok(())
+    }
+
+    pub fn set_default(&self, n: &str) -> bool {
+        let patterns_lock = self.patterns.read().unwrap();
+        if !patterns_lock.contains_key(n) {
+            return false;
+        }
+        *self.default_pattern.write().unwrap() = Some(n.to_owned());
+        self.with_db(|conn| storage::update_default_pattern(conn, Some(n)));
+        true
+    }
+
+    // Patterns are edited on a copy which replaces them once it is built, like
+    // a reloaded wordlist
+    pub fn add_words(&self, n: &str, words: Vec<String>, client: &str) -> Result<(), Error> {
+        self.edit_pattern(n, client, |p| p.try_extend(words))
+    }
+
+    pub fn add_exceptions(&self, n: &str, words: Vec<String>, client: &str) -> Result<(), Error> {
+        self.edit_pattern(n, client, |p| {
+            p.add_exceptions(words);
+            Ok(())
+        })
+    }
+
+    // Returns the exceptions the pattern did not have
+    pub fn remove_exceptions(
+        &self,
+        n: &str,
+        words: &[String],
+        client: &str,
+    ) -> Result<Vec<String>, Error> {
+        self.edit_pattern(n, client, |p| Ok(p.remove_exceptions(words)))
+    }
+
+    // Returns the words the pattern did not have
+    pub fn remove_words(
+        &self,
+        n: &str,
+        words: &[String],
+        client: &str,
+    ) -> Result<Vec<String>, Error> {
+        self.edit_pattern(n, client, |p| p.try_remove(words))
+    }
+
+    fn edit_pattern<T>(
+        &self,
+        n: &str,
+        client: &str,
+        f: impl FnOnce(&mut MatchPattern) -> Result<T, PatternError>,
+    ) -> Result<T, Error> {
+        let Some(p) = self.get(n) else {
+            return Err(Error::UnknownPattern { name: n.to_owned() });
+        };
+        let mut edits = self.edits.lock().unwrap();
+        let mut new = p.read().unwrap().clone();
+        let res = f(&mut new).map_err(|e| Error::InvalidPattern {
+            name: n.to_owned(),
+            reason: e.to_string(),
+        })?;
+        let definition = PatternDef::from(&new);
+        *p.write().unwrap() = new;
+        *edits += 1;
+        self.with_db(|conn| {
+            storage::save_pattern_revision(conn, n, client, &definition);
+        });
+        Ok(res)
+    }
+
+    pub fn revisions(&self, n: &str) -> Result<Vec<RevisionInfo>, Error> {
+        if !self.patterns.read().unwrap().contains_key(n) {
+            return Err(Error::UnknownPattern { name: n.to_owned() });
+        }
+        let Some(db) = &self.db else {
+            return Ok(vec![]);
+        };
+        let revisions = storage::get_revisions(&db.lock().unwrap(), n);
+
+        // A revision which can't be read anymore has no diff on either side
+        let mut prev: Option<&PatternDef> = None;
+        let mut infos = vec![];
+        for r in &revisions {
+            let definition = r.definition.as_ref().ok();
+            infos.push(RevisionInfo {
+                revision: r.revision,
+                time: r.time.clone(),
+                client: r.client.clone(),
+                diff: prev.zip(definition).map(|(p, d)| p.diff(d)),
+            });
+            prev = definition;
+        }
+        Ok(infos)
+    }
+
+    pub fn diff(&self, n: &str, from: u64, to: u64) -> Result<PatternDiff, Error> {
+        let Some(db) = &self.db else {
+            return Err(Error::UnknownRevision {
+                name: n.to_owned(),
+                revision: from,
+            });
+        };
+        let conn = db.lock().unwrap();
+        let get = |revision| {
+            storage::get_revision(&conn, n, revision)
+                .ok_or(Error::UnknownRevision {
+                    name: n.to_owned(),
+                    revision,
+                })?
+                .definition
+                .map_err(|reason| Error::InvalidPattern {
+                    name: n.to_owned(),
+                    reason,
+                })
+        };
+        Ok(get(from)?.diff(&get(to)?))
+    }
+
+    // The revision is rebuilt like an imported pattern and swapped in whole.
+    // The database stays locked until the rollback is recorded as a revision
+    // of its own, so no other change can slip in between.
+    pub fn rollback(&self, n: &str, revision: u64, client: &str) -> Result<u64, Error> {
+        let Some(p) = self.get(n) else {
+            return Err(Error::UnknownPattern { name: n.to_owned() });
+        };
+        let unknown = || Error::UnknownRevision {
+            name: n.to_owned(),
+            revision,
+        };
+        let Some(db) = &self.db else {
+            return Err(unknown());
+        };
+        let mut edits = self.edits.lock().unwrap();
+        let conn = db.lock().unwrap();
+        let def = storage::get_revision(&conn, n, revision)
+            .ok_or_else(unknown)?
+            .definition
+            .map_err(PatternError::MalformedDefinition);
+        let new = def
+            .and_then(MatchPattern::try_from)
+            .map_err(|e| Error::InvalidPattern {
+                name: n.to_owned(),
+                reason: e.to_string(),
+            })?;
+
+        let definition = PatternDef::from(&new);
+        let new_revision = storage::save_pattern_revision(&conn, n, client, &definition);
+        *p.write().unwrap() = new;
+        *edits += 1;
+        Ok(new_revision)
+    }
+
+    pub fn enabled_patterns(&self) -> Arc<[(String, LockedPattern)]> {
+        self.enabled.read().unwrap().clone()
+    }
+
+    // A channel without bindings of its own gets the patterns bound to
+    // `ANY_CHANNEL`, and when that has none either, the enabled patterns which
+    // are not bound to any channel
+    pub fn patterns_for(&self, channel: &str) -> Vec<(String, LockedPattern)> {
+        let enabled = self.enabled_patterns();
+        let bindings_lock = self.bindings.read().unwrap();
+        let bound = bindings_lock
+            .get(&normalize_channel(channel))
+            .or_else(|| bindings_lock.get(ANY_CHANNEL));
+
+        match bound {
+            Some(names) => enabled
+                .iter()
+                .filter(|(n, _)| names.contains(n))
+                .cloned()
+                .collect(),
+            None => {
+                drop(bindings_lock);
+                self.unbound.read().unwrap().to_vec()
+            }
+        }
+    }
+
+    pub fn bind(&self, n: &str, channels: &[String]) -> bool {
+        if !self.patterns.read().unwrap().contains_key(n) {
+            return false;
+        }
+        let mut bindings_lock = self.bindings.write().unwrap();
+        for ch in channels {
+            let ch = normalize_channel(ch);
+            self.with_db(|conn| storage::insert_binding(conn, &ch, n));
+            bindings_lock.entry(ch).or_default().insert(n.to_owned());
+        }
+        drop(bindings_lock);
+        self.update_unbound();
+        true
+    }
+
+    // Unbinds the pattern from every channel when none are given
+    pub fn unbind(&self, n: &str, channels: &[String]) -> bool {
+        if !self.patterns.read().unwrap().contains_key(n) {
+            return false;
+        }
+        let channels = channels
+            .iter()
+            .map(|ch| normalize_channel(ch))
+            .collect::<Vec<_>>();
+        let mut bindings_lock = self.bindings.write().unwrap();
+        bindings_lock.retain(|ch, names| {
+            if (channels.is_empty() || channels.contains(ch)) && names.remove(n) {
+                self.with_db(|conn| storage::delete_binding(conn, ch, n));
+            }
+            !names.is_empty()
+        });
+        drop(bindings_lock);
+        self.update_unbound();
+        true
+    }
+
+    fn bound_channels(&self, n: &str) -> Vec<String> {
+        let mut channels = self
+            .bindings
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, names)| names.contains(n))
+            .map(|(ch, _)| ch.clone())
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    fn update_enabled(&self, patterns: &FnvHashMap<String, PatternEntry>) {
+        let mut enabled = patterns
+            .iter()
+            .filter(|(_, e)| e.enabled)
+            .map(|(n, e)| (n.clone(), e.pattern.clone()))
+            .collect::<Vec<_>>();
+        enabled.sort_by(|a, b| a.0.cmp(&b.0));
+        *self.enabled.write().unwrap() = enabled.into();
+        self.update_unbound();
+    }
+
+    // Called after the enabled patterns or the bindings change. The snapshot
+    // is built under its own lock, so the last change always wins.
+    fn update_unbound(&self) {
+        let mut unbound_lock = self.unbound.write().unwrap();
+        let enabled = self.enabled_patterns();
+        let bindings_lock = self.bindings.read().unwrap();
+        let bound = bindings_lock.values().flatten().collect::<BTreeSet<_>>();
+        *unbound_lock = enabled
+            .iter()
+            .filter(|(n, _)| !bound.contains(n))
+            .cloned()
+            .collect();
+    }
+
+    // Patterns are swapped under a short write lock once their new version is
+    // built, so neither the processor nor edits wait for a file to be read.
+    // A reload which an edit overtook is dropped, the wordlist stays stale and
+    // is read again the next time.
+    pub fn reload_wordlists(&self) -> Vec<(String, PatternError)> {
+        let stale = self
+            .patterns
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| {
+                let p = e.pattern.read().unwrap();
+                p.wordlist().is_some_and(|w| w.is_stale())
+            })
+            .map(|(n, e)| (n.clone(), e.pattern.clone()))
+            .collect::<Vec<_>>();
+
+        let mut errors = vec![];
+        for (n, p) in stale {
+            let seen = *self.edits.lock().unwrap();
+            let current = p.read().unwrap().clone();
+            let reloaded = current.reloaded();
+
+            let edits = self.edits.lock().unwrap();
+            if *edits != seen {
+                continue;
+            }
+            match reloaded {
+                Ok(new) => *p.write().unwrap() = new,
+                Err(e) => {
+                    p.write().unwrap().mark_wordlist_seen();
+                    errors.push((n, e));
+                }
+            }
+        }
+        errors
+    }
+
+    pub fn record_hit(&self, n: &str, channel: &str, report: &MatchReport) {
+        self.stats.record(n, &normalize_channel(channel), report);
+    }
+
+    // A pattern without hits yet has empty stats
+    pub fn stats(
+        &self,
+        n: Option<&str>,
+        reset: bool,
+    ) -> Result<BTreeMap<String, PatternStats>, Error> {
+        if let Some(n) = n {
+            if !self.patterns.read().unwrap().contains_key(n) {
+                return Err(Error::UnknownPattern { name: n.to_owned() });
+            }
+        }
+        Ok(self.stats.get(n, reset))
+    }
+
+    pub fn default_pattern(&self) -> Option<String> {
+        self.default_pattern.read().unwrap().clone()
+    }
+
+    pub fn info(&self, n: &str) -> Option<PatternInfo> {
+        let patterns_lock = self.patterns.read().unwrap();
+        let default = self.default_pattern();
+        patterns_lock
+            .get_key_value(n)
+            .map(|(n, e)| self.entry_info(n, e, default.as_deref()))
+    }
+
+    pub fn list(&self) -> Vec<PatternInfo> {
+        let patterns_lock = self.patterns.read().unwrap();
+        let default = self.default_pattern();
+
+        let mut list = patterns_lock
+            .iter()
+            .map(|(n, e)| self.entry_info(n, e, default.as_deref()))
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    fn entry_info(&self, n: &str, e: &PatternEntry, default: Option<&str>) -> PatternInfo {
+        let p = e.pattern.read().unwrap();
+        PatternInfo {
+            name: n.to_owned(),
+            default: default == Some(n),
+            enabled: e.enabled,
+            channels: self.bound_channels(n),
+            raw_pattern: RawPattern::from(&*p),
+            config: p.config().clone(),
+            conditions: p.conditions().clone(),
+            exceptions: p.exceptions().to_vec(),
+        }
     }
 }
 
@@ -73,3 +601,210 @@ impl Default for PatternStorage {
         PatternStorage::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_pattern::MatchMode;
+
+    #[test]
+    fn bindings() {
+        let storage = PatternStorage::new();
+        for n in ["ru", "jp", "common"] {
+            storage
+                .add(n.to_owned(), MatchPattern::new(), false, "test")
+                .unwrap();
+        }
+        let names = |channel| {
+            storage
+                .patterns_for(channel)
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("esl_dota2"), ["common", "jp", "ru"]);
+
+        assert!(storage.bind("ru", &["#ESL_Dota2".to_owned()]));
+        assert!(storage.bind("jp", &["valorant_jpn".to_owned()]));
+        assert!(!storage.bind("en", &["esl_dota2".to_owned()]));
+        assert_eq!(names("esl_dota2"), ["ru"]);
+        assert_eq!(names("forsen"), ["common"]);
+
+        assert!(storage.unbind("jp", &[]));
+        assert_eq!(names("forsen"), ["common", "jp"]);
+        assert!(storage.bind("jp", &["valorant_jpn".to_owned()]));
+
+        assert!(storage.bind("common", &[ANY_CHANNEL.to_owned()]));
+        assert_eq!(names("forsen"), ["common"]);
+        assert_eq!(names("valorant_jpn"), ["jp"]);
+
+        storage.set_enabled("jp", false);
+        assert!(names("valorant_jpn").is_empty());
+
+        assert!(storage.unbind("ru", &[]));
+        assert_eq!(names("esl_dota2"), ["common"]);
+
+        assert!(storage.unbind("common", &[]));
+        storage.rename("ru", "ru_ua".to_owned()).unwrap();
+        assert_eq!(names("forsen"), ["common", "ru_ua"]);
+        assert!(storage.remove("common"));
+        assert_eq!(names("forsen"), ["ru_ua"]);
+    }
+
+    #[test]
+    fn management() {
+        let storage = PatternStorage::new();
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .words(["^scam"])
+            .build();
+        storage.add("spam".to_owned(), p, false, "test").unwrap();
+        storage
+            .add("other".to_owned(), MatchPattern::new(), false, "test")
+            .unwrap();
+        storage.bind("spam", &["forsen".to_owned()]);
+
+        assert!(storage.rename("spam", "other".to_owned()).is_err());
+        storage.rename("spam", "scam".to_owned()).unwrap();
+        let info = storage.info("scam").unwrap();
+        assert!(info.default);
+        assert_eq!(info.channels, ["forsen"]);
+
+        assert!(storage
+            .add_words("scam", vec!["(nitro".to_owned()], "test")
+            .is_err());
+        storage
+            .add_words("scam", vec!["nitro$".to_owned()], "test")
+            .unwrap();
+        assert!(storage
+            .get("scam")
+            .unwrap()
+            .read()
+            .unwrap()
+            .match_str("free nitro"));
+        let missing = storage
+            .remove_words(
+                "scam",
+                &["^scam", "gift", "^scam"].map(String::from),
+                "test",
+            )
+            .unwrap();
+        assert_eq!(missing, ["gift", "^scam"]);
+
+        assert!(storage.set_default("other"));
+        assert!(storage.remove("scam"));
+        assert!(!storage.remove("scam"));
+        assert!(storage
+            .patterns_for("forsen")
+            .iter()
+            .all(|(n, _)| n == "other"));
+    }
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("chatspy-patterns-{}", std::process::id()));
+        let open = || {
+            let conn = Connection::open(&path).unwrap();
+            storage::run_init_migration(&conn);
+            let (storage, errors) = PatternStorage::open(conn);
+            assert!(errors.is_empty());
+            storage
+        };
+
+        let storage = open();
+        let p = MatchPattern::builder().words(["scam"]).build();
+        storage.add("spam".to_owned(), p, false, "test").unwrap();
+        storage
+            .add("other".to_owned(), MatchPattern::new(), true, "test")
+            .unwrap();
+        storage
+            .add_words("spam", vec!["free nitro".to_owned()], "test")
+            .unwrap();
+        storage.bind("spam", &["forsen".to_owned(), "xqc".to_owned()]);
+        storage.unbind("spam", &["xqc".to_owned()]);
+        storage.rename("spam", "scam".to_owned()).unwrap();
+        storage.set_enabled("other", false);
+        drop(storage);
+
+        let storage = open();
+        let names = storage
+            .list()
+            .into_iter()
+            .map(|i| (i.name, i.default, i.enabled, i.channels))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ("other".to_owned(), true, false, vec![]),
+                ("scam".to_owned(), false, true, vec!["forsen".to_owned()]),
+            ]
+        );
+        let p = storage.get("scam").unwrap();
+        assert!(p.read().unwrap().match_str("get free nitro"));
+
+        storage.remove("scam");
+        drop(storage);
+        assert_eq!(open().list().len(), 1);
+
+        // Rows which don't load anymore are reported and left in place
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "INSERT INTO patterns (name, definition, is_default, enabled) \
+            VALUES ('new', '{\"version\":2,\"mode\":\"Wildcard\"}', false, true)",
+            (),
+        )
+        .unwrap();
+        let (storage, errors) = PatternStorage::open(conn);
+        assert_eq!(storage.list().len(), 1);
+        assert!(matches!(&errors[..], [(n, PatternError::MalformedDefinition(_))] if n == "new"));
+        assert_eq!(
+            storage::get_patterns(&storage.db.unwrap().lock().unwrap()).len(),
+            2
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn revisions() {
+        let path = std::env::temp_dir().join(format!("chatspy-revisions-{}", std::process::id()));
+        let conn = Connection::open(&path).unwrap();
+        storage::run_init_migration(&conn);
+        let storage = PatternStorage::open(conn).0;
+
+        let p = MatchPattern::builder().words(["scam", "gift"]).build();
+        storage.add("spam".to_owned(), p, false, "alice").unwrap();
+        storage
+            .add_words("spam", vec!["free nitro".to_owned()], "bob")
+            .unwrap();
+        storage
+            .remove_words("spam", &["gift".to_owned()], "bob")
+            .unwrap();
+
+        let revisions = storage.revisions("spam").unwrap();
+        let clients = revisions
+            .iter()
+            .map(|r| (r.revision, r.client.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(clients, [(1, "alice"), (2, "bob"), (3, "bob")]);
+        assert!(revisions[0].diff.is_none());
+        assert_eq!(revisions[2].diff.as_ref().unwrap().removed_words, ["gift"]);
+
+        let diff = storage.diff("spam", 1, 3).unwrap();
+        assert_eq!(diff.added_words, ["free nitro"]);
+        assert_eq!(diff.removed_words, ["gift"]);
+        assert!(diff.changed.is_empty());
+        assert!(storage.diff("spam", 1, 4).is_err());
+
+        assert_eq!(storage.rollback("spam", 1, "carol").unwrap(), 4);
+        let p = storage.get("spam").unwrap();
+        assert_eq!(*p.read().unwrap().words(), ["scam", "gift"]);
+        assert!(storage.diff("spam", 1, 4).unwrap().is_empty());
+
+        storage.rename("spam", "scam".to_owned()).unwrap();
+        assert_eq!(storage.revisions("scam").unwrap().len(), 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
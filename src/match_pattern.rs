@@ -1,24 +1,312 @@
+use crate::match_pattern::case::CaseMode;
+use crate::match_pattern::conditions::{ConditionMatcher, Conditions};
+use crate::match_pattern::glob::Glob;
 use crate::match_pattern::match_fns::MatchFnPtr;
-use clap::ValueEnum;
+use crate::match_pattern::normalize::Normalization;
+use crate::match_pattern::query::{Query, QueryError};
+use crate::match_pattern::stem::Language;
+use crate::match_pattern::term_index::TermIndex;
+use crate::match_pattern::wordlist::Wordlist;
+use crate::twitch::UserMessage;
+use aho_corasick::AhoCorasick;
+use regex::{Regex, RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+use std::ops::{ControlFlow, Range};
+use std::path::PathBuf;
+
+pub mod case;
+pub mod conditions;
+pub mod glob;
+pub mod normalize;
+pub mod query;
+pub mod stem;
+mod term_index;
+pub mod wordlist;
 
 mod match_fns {
-    use super::MatchPattern;
-    use std::borrow::Cow;
+    use super::{is_aligned, MatchMode, MatchPattern, Tokens};
+    use std::ops::{ControlFlow, Range};
+
+    // A term found in a message, `span` is a byte range of the original one
+    pub(super) struct Hit {
+        pub(super) term: usize,
+        pub(super) span: Range<usize>,
+    }
 
-    pub(super) type MatchFnInput<'a> = Cow<'a, str>;
-    pub(super) type MatchFnPtr = fn(&MatchPattern, &MatchFnInput) -> bool;
+    pub(super) type OnHit<'a> = &'a mut dyn FnMut(Hit) -> ControlFlow<()>;
+    pub(super) type MatchFnPtr = fn(&MatchPattern, &str, &Tokens, OnHit) -> ControlFlow<()>;
 
-    pub(super) fn match_inclusive(p: &MatchPattern, w: &MatchFnInput) -> bool {
-        p.words
-            .iter()
-            .map(|pattern_w| (w.as_bytes().windows(pattern_w.len()), pattern_w.as_bytes()))
-            .any(|(sub_ws, pattern_w)| sub_ws.map(|w| w == pattern_w).any(|b| b))
+    pub(super) fn match_inclusive(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        if let Some(automaton) = &p.automaton {
+            // The tokens are already joined, so the automaton scans the whole
+            // message in a single pass
+            for m in automaton.find_overlapping_iter(&tokens.text) {
+                let i = tokens.spans.partition_point(|(_, t)| t.start <= m.start()) - 1;
+                let (span, t) = &tokens.spans[i];
+                let span = if is_aligned(&s[span.clone()], &tokens.text[t.clone()]) {
+                    let offset = span.start + m.start() - t.start;
+                    offset..offset + m.len()
+                } else {
+                    span.clone()
+                };
+                on_hit(Hit {
+                    term: p.word_ids[m.pattern().as_usize()],
+                    span,
+                })?;
+            }
+        }
+
+        // Like a word, a phrase may start and end inside of a token
+        match_phrases(p, s, tokens, on_hit, |_, i, n, w, part| match i {
+            0 => w.ends_with(part),
+            i if i == n - 1 => w.starts_with(part),
+            _ => w == part,
+        })
     }
 
-    pub(super) fn match_exclusive(p: &MatchPattern, w: &MatchFnInput) -> bool {
-        p.words.iter().any(|s| s == w)
+    pub(super) fn match_exclusive(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        for (span, w) in tokens.iter() {
+            for &term in p.term_index.get(w) {
+                on_hit(Hit {
+                    term,
+                    span: span.clone(),
+                })?;
+            }
+        }
+        match_phrases(p, s, tokens, on_hit, |_, _, _, w, part| w == part)
+    }
+
+    // Words have to start a token, a phrase has to end with the start of one
+    pub(super) fn match_prefix(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        match_edge(p, s, tokens, on_hit, |w, t| {
+            w.starts_with(t).then_some(0..t.len())
+        })?;
+        match_phrases(p, s, tokens, on_hit, |_, i, n, w, part| {
+            if i == n - 1 {
+                w.starts_with(part)
+            } else {
+                w == part
+            }
+        })
+    }
+
+    pub(super) fn match_suffix(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        match_edge(p, s, tokens, on_hit, |w, t| {
+            w.ends_with(t).then_some(w.len() - t.len()..w.len())
+        })?;
+        match_phrases(p, s, tokens, on_hit, |_, i, _, w, part| {
+            if i == 0 {
+                w.ends_with(part)
+            } else {
+                w == part
+            }
+        })
+    }
+
+    // Compares the tokens without the punctuation around them with every
+    // word, `find` returns the range of the token a word covers
+    fn match_edge(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+        find: impl Fn(&str, &str) -> Option<Range<usize>>,
+    ) -> ControlFlow<()> {
+        for (span, w) in tokens.trimmed(s).filter(|(_, w)| p.min_len <= w.len()) {
+            let aligned = is_aligned(&s[span.clone()], w);
+            for &term in &p.word_ids {
+                if let Some(r) = find(w, &p.terms[term]) {
+                    let span = if aligned {
+                        span.start + r.start..span.start + r.end
+                    } else {
+                        span.clone()
+                    };
+                    on_hit(Hit { term, span })?;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    pub(super) fn match_glob(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        for (span, w) in tokens.trimmed(s) {
+            for &term in p.word_ids.iter().filter(|&&i| p.globs[i][0].matches(w)) {
+                on_hit(Hit {
+                    term,
+                    span: span.clone(),
+                })?;
+            }
+        }
+        match_phrases(p, s, tokens, on_hit, |term, i, _, w, _| {
+            p.globs[term][i].matches(w)
+        })
+    }
+
+    pub(super) fn match_fuzzy(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        let MatchMode::Fuzzy { max_distance } = p.mode else {
+            return ControlFlow::Continue(());
+        };
+        // Tokens whose length is out of reach of every term are never compared
+        let lens = p.min_len.saturating_sub(max_distance)..=p.max_len + max_distance;
+
+        // Buffers for the chars of a token and the rows of the matrix, shared
+        // by every comparison
+        let mut w = vec![];
+        let mut rows = Default::default();
+        for (span, token) in tokens.iter() {
+            w.clear();
+            w.extend(token.chars());
+            if !lens.contains(&w.len()) {
+                continue;
+            }
+            for &term in &p.word_ids {
+                if damerau_within(&p.fuzzy_terms[term][0], &w, max_distance, &mut rows) {
+                    on_hit(Hit {
+                        term,
+                        span: span.clone(),
+                    })?;
+                }
+            }
+        }
+
+        match_phrases(p, s, tokens, on_hit, |term, i, _, token, _| {
+            w.clear();
+            w.extend(token.chars());
+            damerau_within(&p.fuzzy_terms[term][i], &w, max_distance, &mut rows)
+        })
+    }
+
+    // Slides every phrase over the message tokens, `part_eq` compares a token
+    // with the part at the position `i` of the phrase `term` of `n` parts
+    fn match_phrases(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+        mut part_eq: impl FnMut(usize, usize, usize, &str, &str) -> bool,
+    ) -> ControlFlow<()> {
+        if p.phrases.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        let tokens = tokens.trimmed(s).collect::<Vec<_>>();
+        for (term, parts) in &p.phrases {
+            for ws in tokens.windows(parts.len()) {
+                let is_match = ws
+                    .iter()
+                    .zip(parts)
+                    .enumerate()
+                    .all(|(i, ((_, w), part))| part_eq(*term, i, parts.len(), w, part));
+                if is_match {
+                    on_hit(Hit {
+                        term: *term,
+                        span: ws[0].0.start..ws[ws.len() - 1].0.end,
+                    })?;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Optimal string alignment distance between `a` and `b` compared against
+    // `max`, gives up as soon as a whole row of the matrix exceeds it. The
+    // last three rows are kept in `rows`, so that callers can reuse them.
+    pub(super) fn damerau_within(
+        a: &[char],
+        b: &[char],
+        max: usize,
+        rows: &mut [Vec<usize>; 3],
+    ) -> bool {
+        if a.len().abs_diff(b.len()) > max {
+            return false;
+        }
+        if a == b {
+            return true;
+        }
+
+        let n = b.len();
+        let [prev2, prev, cur] = rows;
+        for row in [&mut *prev2, &mut *prev, &mut *cur] {
+            row.clear();
+            row.resize(n + 1, 0);
+        }
+        prev.iter_mut().enumerate().for_each(|(j, d)| *d = j);
+
+        for i in 1..=a.len() {
+            cur[0] = i;
+            let mut row_min = i;
+            for j in 1..=n {
+                let cost = usize::from(a[i - 1] != b[j - 1]);
+                cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    cur[j] = cur[j].min(prev2[j - 2] + 1);
+                }
+                row_min = row_min.min(cur[j]);
+            }
+            if row_min > max {
+                return false;
+            }
+            std::mem::swap(prev2, prev);
+            std::mem::swap(prev, cur);
+        }
+
+        prev[n] <= max
+    }
+
+    // Expressions see the tokens as they were written, folding them would
+    // change what the expression was written against
+    pub(super) fn match_regex(
+        p: &MatchPattern,
+        s: &str,
+        tokens: &Tokens,
+        on_hit: OnHit,
+    ) -> ControlFlow<()> {
+        let Some(Ok(set)) = &p.regex_set else {
+            return ControlFlow::Continue(());
+        };
+        for (span, _) in tokens.iter() {
+            for term in set.matches(&s[span.clone()]).iter() {
+                on_hit(Hit {
+                    term,
+                    span: span.clone(),
+                })?;
+            }
+        }
+        ControlFlow::Continue(())
     }
 }
 
@@ -26,11 +314,19 @@ trait MatchFnDispatcher {
     fn dispatch_match_fn(&self) -> MatchFnPtr;
 }
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, ValueEnum)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MatchMode {
     #[default]
     Inclusive,
     Exclusive,
+    Regex,
+    Fuzzy {
+        max_distance: usize,
+    },
+    // These compare tokens without the punctuation around them
+    Prefix,
+    Suffix,
+    Glob,
 }
 
 impl MatchFnDispatcher for MatchMode {
@@ -38,14 +334,356 @@ impl MatchFnDispatcher for MatchMode {
         match self {
             MatchMode::Inclusive => match_fns::match_inclusive,
             MatchMode::Exclusive => match_fns::match_exclusive,
+            MatchMode::Regex => match_fns::match_regex,
+            MatchMode::Fuzzy { .. } => match_fns::match_fuzzy,
+            MatchMode::Prefix => match_fns::match_prefix,
+            MatchMode::Suffix => match_fns::match_suffix,
+            MatchMode::Glob => match_fns::match_glob,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PatternConfig {
+    pub ignore_chars: String,
+    #[serde(
+        alias = "case_sensitive",
+        deserialize_with = "case::deserialize_case_mode"
+    )]
+    pub case: CaseMode,
+    pub min_token_len: Option<usize>,
+    pub normalization: Normalization,
+    // Last stage, skipped in regex and glob modes
+    pub stemmer: Option<Language>,
+    // Words missing from `weights` weigh 1, a pattern without a threshold
+    // fires on any hit
+    pub weights: BTreeMap<String, f64>,
+    pub threshold: Option<f64>,
+}
+
+// Whether every byte of a formatted token stays at the same offset as in the
+// source one, which lets a match inside of it be mapped back to the source
+fn is_aligned(src: &str, formatted: &str) -> bool {
+    src == formatted || src.len() == formatted.len() && src.is_ascii() && formatted.is_ascii()
+}
+
+// Narrows a token down to the chars between the punctuation around it, returns
+// its span in the message and the range of `w` which is left
+fn trim_token(str: &str, span: Range<usize>, w: &str) -> (Range<usize>, Range<usize>) {
+    let is_edge = |c: char| !c.is_alphanumeric();
+    let start = w.len() - w.trim_start_matches(is_edge).len();
+    let end = start + w[start..].trim_end_matches(is_edge).len();
+    if end - start == w.len() {
+        return (span, 0..w.len());
+    }
+
+    let span = if is_aligned(&str[span.clone()], w) {
+        span.start + start..span.start + end
+    } else {
+        span
+    };
+    (span, start..end)
+}
+
+// Formatted tokens of a message written one after another into a single
+// buffer, each one followed by a space, along with their spans in the message
+// and their ranges in the buffer
+#[derive(Default)]
+struct Tokens {
+    text: String,
+    spans: Vec<(Range<usize>, Range<usize>)>,
+}
+
+thread_local! {
+    // Taken out while a message is matched, so a query term matched in the
+    // middle of it finds it empty and uses its own
+    static TOKENS: Cell<Tokens> = Cell::default();
+}
+
+impl Tokens {
+    fn iter(&self) -> impl Iterator<Item = (Range<usize>, &str)> {
+        self.spans
+            .iter()
+            .map(|(span, t)| (span.clone(), &self.text[t.clone()]))
+    }
+
+    // Same as `MatchPattern::phrase_tokens`
+    fn trimmed<'a>(&'a self, str: &'a str) -> impl Iterator<Item = (Range<usize>, &'a str)> {
+        self.iter()
+            .map(|(span, w)| {
+                let (span, r) = trim_token(str, span, w);
+                (span, &w[r])
+            })
+            .filter(|(_, w)| !w.is_empty())
+    }
+}
+
+// Whether a hit overlaps one of the exception tokens of a message
+fn is_excepted(exceptions: &[Range<usize>], hit: &Range<usize>) -> bool {
+    exceptions
+        .iter()
+        .any(|e| e.start < hit.end && hit.start < e.end)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MatchHit {
+    pub word: String,
+    pub start: usize,
+    pub end: usize,
+    pub mode: MatchMode,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+// `suppressed` holds the hits discarded because they were inside of an
+// exception, they add nothing to the score
+pub struct MatchReport {
+    pub hits: Vec<MatchHit>,
+    pub score: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suppressed: Vec<MatchHit>,
+}
+
+#[derive(Debug)]
+pub enum PatternError {
+    InvalidRegex { word: String, reason: String },
+    InvalidQuery(QueryError),
+    UnsupportedVersion(u32),
+    ConflictingSources,
+    NotEditable,
+    ReadFailed { path: PathBuf, reason: String },
+    MalformedDefinition(String),
+    EmptyWord(String),
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternError::InvalidRegex { word, reason } => {
+                write!(f, "invalid regular expression '{}': {}", word, reason)
+            }
+            PatternError::InvalidQuery(e) => write!(f, "invalid query: {}", e),
+            PatternError::UnsupportedVersion(v) => {
+                write!(f, "unsupported pattern version {}", v)
+            }
+            PatternError::ConflictingSources => {
+                write!(f, "a pattern is made of either words, a query or a file")
+            }
+            PatternError::NotEditable => {
+                write!(f, "words of a query or a file pattern cannot be edited")
+            }
+            PatternError::ReadFailed { path, reason } => {
+                write!(f, "failed to read '{}': {}", path.display(), reason)
+            }
+            PatternError::MalformedDefinition(reason) => {
+                write!(f, "malformed definition: {}", reason)
+            }
+            PatternError::EmptyWord(word) => {
+                write!(f, "word '{}' is empty once formatted", word)
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+pub const PATTERN_DEF_VERSION: u32 = 1;
+
+// Stable serialized form of a pattern. Only what the pattern was created from
+// is kept, the rest is rebuilt and validated when it is turned back into one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PatternDef {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub words: Vec<String>,
+    #[serde(default)]
+    pub mode: MatchMode,
+    #[serde(default)]
+    pub config: PatternConfig,
+    #[serde(default)]
+    pub conditions: Conditions,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exceptions: Vec<String>,
+}
+
+impl From<&MatchPattern> for PatternDef {
+    fn from(p: &MatchPattern) -> Self {
+        PatternDef {
+            version: PATTERN_DEF_VERSION,
+            query: p.query.as_ref().map(|q| q.source().to_owned()),
+            file: p.wordlist.as_ref().map(|w| w.path().to_owned()),
+            // The words of a file are read from it again
+            words: match p.wordlist {
+                Some(_) => vec![],
+                None => p.words.clone(),
+            },
+            mode: p.mode,
+            config: p.config.clone(),
+            conditions: p.conditions.clone(),
+            exceptions: p.exceptions.clone(),
+        }
+    }
+}
+
+impl From<MatchPattern> for PatternDef {
+    fn from(p: MatchPattern) -> Self {
+        PatternDef::from(&p)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SettingChange {
+    pub setting: String,
+    pub from: serde_json::Value,
+    pub to: serde_json::Value,
+}
+
+// Words are compared as sets, settings by the dotted path of every changed
+// leaf of their serialized form
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct PatternDiff {
+    pub added_words: Vec<String>,
+    pub removed_words: Vec<String>,
+    pub changed: Vec<SettingChange>,
+}
+
+impl PatternDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_words.is_empty() && self.removed_words.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn diff_values(
+    path: String,
+    from: &serde_json::Value,
+    to: &serde_json::Value,
+    out: &mut Vec<SettingChange>,
+) {
+    use serde_json::Value;
+
+    match (from, to) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for k in keys {
+                let (x, y) = (
+                    a.get(k).unwrap_or(&Value::Null),
+                    b.get(k).unwrap_or(&Value::Null),
+                );
+                let path = if path.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", path, k)
+                };
+                diff_values(path, x, y, out);
+            }
+        }
+        (a, b) if a != b => out.push(SettingChange {
+            setting: path,
+            from: a.clone(),
+            to: b.clone(),
+        }),
+        _ => {}
+    }
+}
+
+impl PatternDef {
+    pub fn diff(&self, other: &PatternDef) -> PatternDiff {
+        let old: HashSet<&String> = self.words.iter().collect();
+        let new: HashSet<&String> = other.words.iter().collect();
+
+        let mut changed = vec![];
+        let settings = |d: &PatternDef| {
+            serde_json::json!({
+                "query": d.query,
+                "file": d.file,
+                "mode": d.mode,
+                "config": d.config,
+                "conditions": d.conditions,
+                "exceptions": d.exceptions,
+            })
+        };
+        diff_values(
+            String::new(),
+            &settings(self),
+            &settings(other),
+            &mut changed,
+        );
+
+        PatternDiff {
+            added_words: other
+                .words
+                .iter()
+                .filter(|w| !old.contains(w))
+                .cloned()
+                .collect(),
+            removed_words: self
+                .words
+                .iter()
+                .filter(|w| !new.contains(w))
+                .cloned()
+                .collect(),
+            changed,
+        }
+    }
+}
+
+impl TryFrom<PatternDef> for MatchPattern {
+    type Error = PatternError;
+
+    fn try_from(def: PatternDef) -> Result<Self, Self::Error> {
+        if def.version != PATTERN_DEF_VERSION {
+            return Err(PatternError::UnsupportedVersion(def.version));
+        }
+
+        let mut p = match (def.query, def.file) {
+            (Some(_), Some(_)) => return Err(PatternError::ConflictingSources),
+            (Some(_), _) | (_, Some(_)) if !def.words.is_empty() => {
+                return Err(PatternError::ConflictingSources)
+            }
+            (Some(q), None) => MatchPattern::from_query(&q, def.config)?,
+            (None, Some(f)) => MatchPattern::from_file(f, def.mode, def.config)?,
+            (None, None) => MatchPattern::builder()
+                .config(def.config)
+                .mode(def.mode)
+                .words(def.words)
+                .try_build()?,
+        };
+        p.set_conditions(def.conditions);
+        p.set_exceptions(def.exceptions);
+        Ok(p)
+    }
+}
+
+// `words` keeps the entries as they were given, `terms` holds their formatted
+// form. Regex mode compiles the raw entries, so they are never lowercased.
+// Entries of several words are phrases, the rest are listed in `word_ids`.
+// A pattern created from a query evaluates it instead of its own words.
+// Conditions on the author and the channel are checked before any of them.
+// Words of a pattern bound to a wordlist file are replaced on its reload.
+// Hits inside of a run of message tokens listed in `exception_terms` are
+// discarded, an exception of several words only covers them in a row.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(into = "PatternDef", try_from = "PatternDef")]
 pub struct MatchPattern {
+    query: Option<Query>,
+    wordlist: Option<Wordlist>,
+    conditions: Conditions,
+    condition_matcher: ConditionMatcher,
     words: Vec<String>,
-    ignore_chars: String,
+    terms: Vec<String>,
+    exceptions: Vec<String>,
+    exception_terms: Vec<Vec<String>>,
+    word_ids: Vec<usize>,
+    phrases: Vec<(usize, Vec<String>)>,
+    weights: Vec<f64>,
+    fuzzy_terms: Vec<Vec<Vec<char>>>,
+    term_index: TermIndex,
+    globs: Vec<Vec<Glob>>,
+    regex_set: Option<Result<RegexSet, regex::Error>>,
+    automaton: Option<AhoCorasick>,
+    config: PatternConfig,
     max_len: usize,
     min_len: usize,
     mode: MatchMode,
@@ -94,6 +732,55 @@ impl MatchPatternBuilder {
         self
     }
 
+    pub fn normalization(mut self, n: Normalization) -> Self {
+        self.pattern.set_normalization(n);
+        self
+    }
+
+    pub fn ignore_chars(mut self, chars: impl Into<String>) -> Self {
+        self.pattern.set_ignore_chars(chars);
+        self
+    }
+
+    pub fn case(mut self, case: CaseMode) -> Self {
+        self.pattern.set_case(case);
+        self
+    }
+
+    pub fn stemmer(mut self, l: Language) -> Self {
+        self.pattern.set_stemmer(Some(l));
+        self
+    }
+
+    pub fn config(mut self, config: PatternConfig) -> Self {
+        self.pattern.set_config(config);
+        self
+    }
+
+    pub fn conditions(mut self, conditions: Conditions) -> Self {
+        self.pattern.set_conditions(conditions);
+        self
+    }
+
+    pub fn exceptions<'a>(
+        mut self,
+        words: impl IntoIterator<Item = impl Into<Cow<'a, str>>>,
+    ) -> Self {
+        self.pattern
+            .set_exceptions(words.into_iter().map(|w| w.into().into_owned()).collect());
+        self
+    }
+
+    pub fn weight(mut self, word: impl Into<String>, weight: f64) -> Self {
+        self.pattern.set_weight(word, weight);
+        self
+    }
+
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.pattern.set_threshold(Some(threshold));
+        self
+    }
+
     pub fn words<'a>(mut self, words: impl IntoIterator<Item = impl Into<Cow<'a, str>>>) -> Self {
         self.pattern.extend(words);
         self
@@ -102,14 +789,34 @@ impl MatchPatternBuilder {
     pub fn build(self) -> MatchPattern {
         self.pattern
     }
+
+    pub fn try_build(self) -> Result<MatchPattern, PatternError> {
+        self.pattern.validate()?;
+        Ok(self.pattern)
+    }
 }
 
 impl MatchPattern {
     pub fn new() -> Self {
         let mode = MatchMode::default();
         MatchPattern {
+            query: None,
+            wordlist: None,
+            conditions: Conditions::default(),
+            condition_matcher: ConditionMatcher::default(),
             words: vec![],
-            ignore_chars: String::new(),
+            terms: vec![],
+            exceptions: vec![],
+            exception_terms: vec![],
+            word_ids: vec![],
+            phrases: vec![],
+            weights: vec![],
+            fuzzy_terms: vec![],
+            term_index: TermIndex::default(),
+            globs: vec![],
+            regex_set: None,
+            automaton: None,
+            config: PatternConfig::default(),
             max_len: 0,
             min_len: 0,
             match_fn: mode.dispatch_match_fn(),
@@ -121,6 +828,70 @@ impl MatchPattern {
         MatchPatternBuilder::new()
     }
 
+    pub fn from_query(q: &str, config: PatternConfig) -> Result<MatchPattern, PatternError> {
+        let mut p = MatchPattern::new();
+        p.query = Some(Query::parse(q, &config).map_err(PatternError::InvalidQuery)?);
+        p.set_config(config);
+        Ok(p)
+    }
+
+    pub fn from_file(
+        path: impl Into<PathBuf>,
+        mode: MatchMode,
+        config: PatternConfig,
+    ) -> Result<MatchPattern, PatternError> {
+        let path = path.into();
+        let (wordlist, words) =
+            Wordlist::read(path.clone()).map_err(|e| PatternError::ReadFailed {
+                path,
+                reason: e.to_string(),
+            })?;
+
+        let mut p = MatchPattern::builder()
+            .config(config)
+            .mode(mode)
+            .words(words)
+            .try_build()?;
+        p.wordlist = Some(wordlist);
+        Ok(p)
+    }
+
+    // Builds a copy with the current words of the wordlist file, so that the
+    // pattern keeps being used while the copy is built
+    pub fn reloaded(&self) -> Result<MatchPattern, PatternError> {
+        let Some(wordlist) = &self.wordlist else {
+            return Ok(self.clone());
+        };
+        let path = wordlist.path().to_owned();
+        let (wordlist, words) =
+            Wordlist::read(path.clone()).map_err(|e| PatternError::ReadFailed {
+                path,
+                reason: e.to_string(),
+            })?;
+
+        let mut p = self.clone();
+        p.words = words;
+        p.on_words_mut();
+        p.validate()?;
+        p.wordlist = Some(wordlist);
+        Ok(p)
+    }
+
+    // Keeps a broken version of the wordlist from being reloaded over again
+    pub(crate) fn mark_wordlist_seen(&mut self) {
+        if let Some(w) = &mut self.wordlist {
+            w.mark_seen();
+        }
+    }
+
+    pub fn wordlist(&self) -> Option<&Wordlist> {
+        self.wordlist.as_ref()
+    }
+
+    pub fn query(&self) -> Option<&Query> {
+        self.query.as_ref()
+    }
+
     pub fn mode(&self) -> MatchMode {
         self.mode
     }
@@ -129,28 +900,139 @@ impl MatchPattern {
         &self.words
     }
 
+    pub fn normalization(&self) -> &Normalization {
+        &self.config.normalization
+    }
+
+    pub fn config(&self) -> &PatternConfig {
+        &self.config
+    }
+
+    pub fn conditions(&self) -> &Conditions {
+        &self.conditions
+    }
+
+    pub fn exceptions(&self) -> &[String] {
+        &self.exceptions
+    }
+
     pub fn set_mode(&mut self, mode: MatchMode) {
         self.match_fn = mode.dispatch_match_fn();
         self.mode = mode;
+        self.on_words_mut();
+    }
+
+    pub fn set_normalization(&mut self, n: Normalization) {
+        self.config.normalization = n;
+        self.on_words_mut();
+    }
+
+    pub fn set_ignore_chars(&mut self, chars: impl Into<String>) {
+        self.config.ignore_chars = chars.into();
+        self.on_words_mut();
+    }
+
+    pub fn set_case(&mut self, case: CaseMode) {
+        self.config.case = case;
+        self.on_words_mut();
+    }
+
+    pub fn set_stemmer(&mut self, stemmer: Option<Language>) {
+        self.config.stemmer = stemmer;
+        self.on_words_mut();
+    }
+
+    pub fn set_config(&mut self, config: PatternConfig) {
+        self.config = config;
+        self.on_words_mut();
+    }
+
+    pub fn set_conditions(&mut self, conditions: Conditions) {
+        self.condition_matcher = ConditionMatcher::from(&conditions);
+        self.conditions = conditions;
+    }
+
+    pub fn set_exceptions(&mut self, exceptions: Vec<String>) {
+        self.exceptions = exceptions;
+        self.on_words_mut();
+    }
+
+    pub fn add_exceptions(&mut self, exceptions: Vec<String>) {
+        self.exceptions.extend(exceptions);
+        self.on_words_mut();
+    }
+
+    // Returns the exceptions which were not found
+    pub fn remove_exceptions(&mut self, exceptions: &[String]) -> Vec<String> {
+        let mut missing = vec![];
+        for e in exceptions {
+            let terms = self.format_exception(e);
+            match self
+                .exceptions
+                .iter()
+                .position(|x| self.format_exception(x) == terms)
+            {
+                Some(p) => {
+                    self.exceptions.remove(p);
+                }
+                None => missing.push(e.clone()),
+            }
+        }
+        self.on_words_mut();
+        missing
+    }
+
+    pub fn set_weight(&mut self, word: impl Into<String>, weight: f64) {
+        self.config.weights.insert(word.into(), weight);
+        self.on_words_mut();
+    }
+
+    pub fn set_threshold(&mut self, threshold: Option<f64>) {
+        self.config.threshold = threshold;
+    }
+
+    // An empty term would match at every position of every message.
+    // Expressions which are valid on their own can still add up to a set
+    // over the size limit, which is reported for the whole set.
+    pub fn validate(&self) -> Result<(), PatternError> {
+        if let Some(i) = self.terms.iter().position(|t| t.is_empty()) {
+            return Err(PatternError::EmptyWord(self.words[i].clone()));
+        }
+        if let Some(Err(e)) = &self.regex_set {
+            let words = match e {
+                regex::Error::Syntax(_) => self.words.as_slice(),
+                _ => &[],
+            };
+            for w in words {
+                if let Err(e) = Regex::new(w) {
+                    return Err(PatternError::InvalidRegex {
+                        word: w.clone(),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            return Err(PatternError::InvalidRegex {
+                word: format!("set of {} expressions", self.words.len()),
+                reason: e.to_string(),
+            });
+        }
+        Ok(())
     }
 
     pub fn extend<'a>(&mut self, words: impl IntoIterator<Item = impl Into<Cow<'a, str>>>) {
-        let words_iter = words
-            .into_iter()
-            .map(|s| self.format_word(s).to_string())
-            .collect::<Vec<_>>();
-        self.words.extend(words_iter);
+        self.words
+            .extend(words.into_iter().map(|s| s.into().into_owned()));
         self.on_words_mut();
     }
 
     pub fn insert(&mut self, word: &str) {
-        let word = self.format_word(word).to_string();
-        self.words.push(word);
+        self.words.push(word.to_owned());
         self.on_words_mut();
     }
 
     pub fn remove(&mut self, word: &str) -> bool {
-        if let Some(p) = self.words.iter().position(|s| s == word) {
+        let word = self.format_entry(word);
+        if let Some(p) = self.terms.iter().position(|s| *s == word) {
             self.words.swap_remove(p);
             self.on_words_mut();
             true
@@ -159,6 +1041,48 @@ impl MatchPattern {
         }
     }
 
+    // Unlike `extend`, keeps the pattern as it was when a word is invalid
+    pub fn try_extend(&mut self, words: Vec<String>) -> Result<(), PatternError> {
+        self.check_editable()?;
+        let mut p = self.clone();
+        p.extend(words);
+        p.validate()?;
+        *self = p;
+        Ok(())
+    }
+
+    // Returns the words which were not found. Like `remove`, each word takes
+    // out a single entry, the pattern is rebuilt once they are all gone.
+    pub fn try_remove(&mut self, words: &[String]) -> Result<Vec<String>, PatternError> {
+        self.check_editable()?;
+        let mut removed = vec![false; self.terms.len()];
+        let missing = words
+            .iter()
+            .filter(|w| {
+                let w = self.format_entry(w);
+                let p = (0..self.terms.len()).find(|&i| !removed[i] && self.terms[i] == w);
+                p.map(|p| removed[p] = true).is_none()
+            })
+            .cloned()
+            .collect();
+
+        if removed.contains(&true) {
+            let mut removed = removed.into_iter();
+            self.words.retain(|_| !removed.next().unwrap());
+            self.on_words_mut();
+        }
+        Ok(missing)
+    }
+
+    // Words of a pattern made from a query or a file are not its own to edit
+    fn check_editable(&self) -> Result<(), PatternError> {
+        if self.query.is_some() || self.wordlist.is_some() {
+            Err(PatternError::NotEditable)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn remove_position(&mut self, p: usize) -> bool {
         if p > self.words.len() {
             return false;
@@ -169,27 +1093,401 @@ impl MatchPattern {
     }
 
     pub fn match_str(&self, str: &str) -> bool {
-        str.split(' ')
-            .map(|w| self.format_word(w))
-            .filter(|s| self.min_len <= s.len())
-            .any(|s| (self.match_fn)(self, &s))
+        match (&self.query, self.config.threshold) {
+            (Some(_), Some(_)) => self.match_report(str).is_some(),
+            (Some(q), None) => q.matches(str),
+            (None, None) => self
+                .find_hits(str, &mut |_, excepted| {
+                    if excepted {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(())
+                    }
+                })
+                .is_break(),
+            // Stops as soon as the distinct terms hit add up to the threshold,
+            // words formatting to the same term count once
+            (None, Some(threshold)) => {
+                let mut seen = Vec::<&str>::new();
+                let mut score = 0.0;
+                self.find_hits(str, &mut |h, excepted| {
+                    if excepted {
+                        return ControlFlow::Continue(());
+                    }
+                    let term = self.terms[h.term].as_str();
+                    if !seen.contains(&term) {
+                        seen.push(term);
+                        score += self.weights[h.term];
+                    }
+                    if score >= threshold {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                })
+                .is_break()
+            }
+        }
     }
 
-    fn on_words_mut(&mut self) {
-        self.words.shrink_to_fit();
-        (self.min_len, self.max_len) = MatchPattern::get_minmax_len(&self.words);
+    pub fn match_report(&self, str: &str) -> Option<MatchReport> {
+        // Terms of a query belong to patterns of their own, so their weights
+        // are looked up by word
+        let hits = match &self.query {
+            Some(q) => {
+                let hits = q.hits(str)?;
+                let exceptions = self.with_tokens(str, |tokens| self.exception_spans(str, tokens));
+                hits.into_iter()
+                    .map(|h| {
+                        let excepted = is_excepted(&exceptions, &(h.start..h.end));
+                        (None, h, excepted)
+                    })
+                    .collect()
+            }
+            None => self
+                .all_hits(str)
+                .into_iter()
+                .map(|(term, h, excepted)| (Some(term), h, excepted))
+                .collect::<Vec<_>>(),
+        };
+
+        let (mut hits, mut suppressed): (Vec<_>, Vec<_>) =
+            hits.into_iter().partition(|(_, _, excepted)| !excepted);
+        if hits.is_empty() {
+            return None;
+        }
+        hits.sort_by_key(|(_, h, _)| (h.start, h.end));
+        suppressed.sort_by_key(|(_, h, _)| (h.start, h.end));
+
+        let mut terms = hits
+            .iter()
+            .map(|(term, h, _)| match term {
+                Some(t) => (Cow::from(&self.terms[*t]), self.weights[*t]),
+                None => (self.format_entry(&h.word).into(), self.weight(&h.word)),
+            })
+            .collect::<Vec<_>>();
+        terms.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        terms.dedup_by(|(a, _), (b, _)| a == b);
+        let score = terms.into_iter().map(|(_, weight)| weight).sum::<f64>();
+
+        match self.config.threshold {
+            Some(threshold) if score < threshold => None,
+            _ => Some(MatchReport {
+                hits: hits.into_iter().map(|(_, h, _)| h).collect(),
+                score,
+                suppressed: suppressed.into_iter().map(|(_, h, _)| h).collect(),
+            }),
+        }
+    }
+
+    // Hits of the words of a pattern along with their terms and whether an
+    // exception covers them
+    fn all_hits(&self, str: &str) -> Vec<(usize, MatchHit, bool)> {
+        let mut hits = vec![];
+        let _ = self.find_hits(str, &mut |h, excepted| {
+            hits.push((
+                h.term,
+                MatchHit {
+                    word: self.words[h.term].clone(),
+                    start: h.span.start,
+                    end: h.span.end,
+                    mode: self.mode,
+                },
+                excepted,
+            ));
+            ControlFlow::Continue(())
+        });
+        hits
+    }
+
+    // A pattern without words only consists of its conditions, so it catches
+    // every message they let through
+    pub fn match_message(&self, msg: &UserMessage) -> Option<MatchReport> {
+        if !self.condition_matcher.matches(msg) {
+            return None;
+        }
+        if self.query.is_none() && self.words.is_empty() {
+            return (!self.conditions.is_empty()).then(MatchReport::default);
+        }
+        self.match_report(&msg.message)
+    }
+
+    // Weights are looked up by the formatted word, so `Scam` weighs the same
+    // as `scam` unless the pattern is case sensitive
+    pub fn weight(&self, word: &str) -> f64 {
+        let word = self.format_entry(word);
+        self.config
+            .weights
+            .iter()
+            .find(|(w, _)| self.format_entry(w) == word)
+            .map_or(1.0, |(_, weight)| *weight)
+    }
+
+    // Formatted tokens of `str` along with their byte ranges in it. Only the
+    // entries of a pattern are split with it, messages go through `tokenize`.
+    fn tokens<'a>(
+        &'a self,
+        str: &'a str,
+    ) -> impl Iterator<Item = (Range<usize>, Cow<'a, str>)> + 'a {
+        let min_token_len = self.config.min_token_len.unwrap_or(0);
+        str.split_whitespace()
+            .map(move |w| {
+                let start = w.as_ptr() as usize - str.as_ptr() as usize;
+                (start..start + w.len(), self.format_word(w))
+            })
+            .filter(move |(_, w)| min_token_len == 0 || w.chars().count() >= min_token_len)
     }
 
-    fn get_minmax_len(words: &Vec<String>) -> (usize, usize) {
-        if words.is_empty() {
-            return (0, 0);
+    // Phrases are compared without the punctuation around their parts, so
+    // tokens made of punctuation only are skipped
+    fn phrase_tokens<'a>(
+        &'a self,
+        str: &'a str,
+    ) -> impl Iterator<Item = (Range<usize>, Cow<'a, str>)> + 'a {
+        self.tokens(str)
+            .map(|(span, w)| {
+                let (span, r) = trim_token(str, span, &w);
+                let w = match w {
+                    Cow::Borrowed(w) => Cow::Borrowed(&w[r]),
+                    Cow::Owned(w) if r.len() == w.len() => Cow::Owned(w),
+                    Cow::Owned(w) => Cow::Owned(w[r].to_owned()),
+                };
+                (span, w)
+            })
+            .filter(|(_, w)| !w.is_empty())
+    }
+
+    // Same tokens as `tokens` yields, written into the buffers of `tokens`
+    // which are reused from one message to the next
+    fn tokenize(&self, str: &str, tokens: &mut Tokens) {
+        tokens.text.clear();
+        tokens.spans.clear();
+        let min_token_len = self.config.min_token_len.unwrap_or(0);
+        for w in str.split_whitespace() {
+            let start = tokens.text.len();
+            self.push_formatted(w, &mut tokens.text);
+            if min_token_len > 0 && tokens.text[start..].chars().count() < min_token_len {
+                tokens.text.truncate(start);
+                continue;
+            }
+
+            let span_start = w.as_ptr() as usize - str.as_ptr() as usize;
+            tokens
+                .spans
+                .push((span_start..span_start + w.len(), start..tokens.text.len()));
+            tokens.text.push(' ');
+        }
+    }
+
+    // Appends the formatted `w` to `buf`. ASCII words are copied and
+    // lowercased in place, or char by char when some chars are ignored or
+    // rewritten. Anything else, as well as the Turkic case which maps `I` out
+    // of ASCII, goes through `format_word`.
+    fn push_formatted(&self, w: &str, buf: &mut String) {
+        let PatternConfig {
+            ignore_chars,
+            case,
+            normalization,
+            ..
+        } = &self.config;
+
+        if !w.is_ascii() || *case == CaseMode::Turkic {
+            buf.push_str(&self.format_word(w));
+            return;
+        }
+
+        let start = buf.len();
+        let is_folded = *case == CaseMode::Sensitive;
+        match &normalization.leetspeak {
+            None if ignore_chars.is_empty() => {
+                buf.push_str(w);
+                if !is_folded {
+                    buf[start..].make_ascii_lowercase();
+                }
+            }
+            _ => {
+                let leet = normalize::leet_range(w, |c| !ignore_chars.contains(c));
+                buf.extend(
+                    w.char_indices()
+                        .filter(|(_, c)| !ignore_chars.contains(*c))
+                        .map(|(i, c)| {
+                            let c = if is_folded { c } else { c.to_ascii_lowercase() };
+                            if leet.contains(&i) {
+                                normalization.leet(c)
+                            } else {
+                                c
+                            }
+                        }),
+                );
+            }
+        }
+        if let Some(l) = self.stemmer() {
+            let stemmed = match l.stem(Cow::Borrowed(&buf[start..])) {
+                Cow::Owned(stemmed) => stemmed,
+                Cow::Borrowed(_) => return,
+            };
+            buf.truncate(start);
+            buf.push_str(&stemmed);
+        }
+    }
+
+    // Runs `f` over the tokens of `str` formatted into the buffer of this
+    // thread
+    fn with_tokens<T>(&self, str: &str, f: impl FnOnce(&Tokens) -> T) -> T {
+        let mut tokens = TOKENS.take();
+        self.tokenize(str, &mut tokens);
+        let res = f(&tokens);
+        TOKENS.set(tokens);
+        res
+    }
+
+    // Runs the match fn over the tokens of `str`, `on_hit` is told whether
+    // an exception covers a hit
+    fn find_hits(
+        &self,
+        str: &str,
+        on_hit: &mut dyn FnMut(match_fns::Hit, bool) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.with_tokens(str, |tokens| {
+            let exceptions = self.exception_spans(str, tokens);
+            (self.match_fn)(self, str, tokens, &mut |h| {
+                let excepted = is_excepted(&exceptions, &h.span);
+                on_hit(h, excepted)
+            })
+        })
+    }
+
+    // Formatted tokens of an exception, which have to follow each other in a
+    // message
+    fn format_exception(&self, e: &str) -> Vec<String> {
+        self.phrase_tokens(e).map(|(_, t)| t.into_owned()).collect()
+    }
+
+    // Spans of the runs of tokens of `str` listed as exceptions
+    fn exception_spans(&self, str: &str, tokens: &Tokens) -> Vec<Range<usize>> {
+        if self.exception_terms.is_empty() {
+            return vec![];
+        }
+        let tokens = tokens.trimmed(str).collect::<Vec<_>>();
+        let mut spans = vec![];
+        for e in self.exception_terms.iter().filter(|e| !e.is_empty()) {
+            for ws in tokens.windows(e.len()) {
+                if ws.iter().zip(e).all(|((_, w), t)| w == t) {
+                    spans.push(ws[0].0.start..ws[ws.len() - 1].0.end);
+                }
+            }
+        }
+        spans
+    }
+
+    fn format_entry(&self, w: &str) -> String {
+        if w.trim().contains(char::is_whitespace) {
+            let parts = match self.mode {
+                // Stars at the edges of the parts belong to the globs
+                MatchMode::Glob => self.tokens(w).map(|(_, w)| w).collect::<Vec<_>>(),
+                _ => self.phrase_tokens(w).map(|(_, w)| w).collect(),
+            };
+            if !parts.is_empty() {
+                return parts.join(" ");
+            }
         }
+        self.format_word(w.trim()).into_owned()
+    }
+
+    fn on_words_mut(&mut self) {
+        // Every term of a query skips the exceptions on its own, so that a
+        // term they suppress is false in the expression
+        if let Some(q) = &mut self.query {
+            q.set_exceptions(&self.exceptions);
+        }
+        self.words.shrink_to_fit();
+        self.terms = self.words.iter().map(|w| self.format_entry(w)).collect();
+        self.exception_terms = self
+            .exceptions
+            .iter()
+            .map(|e| self.format_exception(e))
+            .collect();
+
+        let is_phrase = |t: &String| self.mode != MatchMode::Regex && t.contains(' ');
+        self.word_ids = (0..self.terms.len())
+            .filter(|&i| !is_phrase(&self.terms[i]))
+            .collect();
+        self.phrases = (0..self.terms.len())
+            .filter(|&i| is_phrase(&self.terms[i]))
+            .map(|i| (i, self.terms[i].split(' ').map(str::to_owned).collect()))
+            .collect();
+
+        let weights = self
+            .config
+            .weights
+            .iter()
+            .map(|(w, weight)| (self.format_entry(w), *weight))
+            .collect::<Vec<_>>();
+        self.weights = self
+            .terms
+            .iter()
+            .map(|t| {
+                weights
+                    .iter()
+                    .find(|(w, _)| w == t)
+                    .map_or(1.0, |(_, w)| *w)
+            })
+            .collect();
 
-        let mut words_len_it = words.iter().map(|w| w.len());
+        let words = self.word_ids.iter().map(|&i| &self.terms[i]);
+        (self.min_len, self.max_len) = match self.mode {
+            // Tokens of any length can match an expression
+            MatchMode::Regex | MatchMode::Glob => (0, 0),
+            _ => MatchPattern::get_minmax_len(words.clone()),
+        };
+        self.regex_set = match self.mode {
+            MatchMode::Regex => Some(
+                RegexSetBuilder::new(&self.words)
+                    .case_insensitive(self.config.case != CaseMode::Sensitive)
+                    .build(),
+            ),
+            _ => None,
+        };
+        // Chars of the parts of every term, `fuzzy_terms[term][i]` is the part
+        // `i` of a term like with globs
+        self.fuzzy_terms = match self.mode {
+            MatchMode::Fuzzy { .. } => self
+                .terms
+                .iter()
+                .map(|t| t.split(' ').map(|part| part.chars().collect()).collect())
+                .collect(),
+            _ => vec![],
+        };
+        self.term_index = match self.mode {
+            MatchMode::Exclusive => {
+                TermIndex::new(self.word_ids.iter().map(|&i| (i, &self.terms[i])))
+            }
+            _ => TermIndex::default(),
+        };
+        // Split into parts like phrases, so that `globs[term][i]` is the part
+        // `i` of a term
+        self.globs = match self.mode {
+            MatchMode::Glob => self
+                .terms
+                .iter()
+                .map(|t| t.split(' ').map(Glob::new).collect())
+                .collect(),
+            _ => vec![],
+        };
+        self.automaton = match self.mode {
+            MatchMode::Inclusive if !self.word_ids.is_empty() => AhoCorasick::new(words).ok(),
+            _ => None,
+        };
+    }
+
+    // Lengths are counted in chars, which never exceeds the length in bytes,
+    // so they can prune byte windows as well as fuzzy candidates
+    fn get_minmax_len<'a>(words: impl IntoIterator<Item = &'a String>) -> (usize, usize) {
+        let mut words_len_it = words.into_iter().map(|w| w.chars().count());
 
-        let (mut min, mut max) = {
-            let l = words_len_it.next().unwrap();
-            (l, l)
+        let (mut min, mut max) = match words_len_it.next() {
+            Some(l) => (l, l),
+            None => return (0, 0),
         };
 
         for l in words_len_it {
@@ -206,19 +1504,38 @@ impl MatchPattern {
     fn format_word<'a>(&self, w: impl Into<Cow<'a, str>>) -> Cow<'a, str> {
         let cow = w.into();
 
+        let PatternConfig {
+            ignore_chars,
+            case,
+            normalization,
+            ..
+        } = &self.config;
+
         let is_correct = cow
             .chars()
-            .all(|c| c.is_lowercase() && !self.ignore_chars.contains(c));
+            .all(|c| case.is_folded(c) && !ignore_chars.contains(c) && normalization.is_stable(c));
 
-        if is_correct {
+        let w = if is_correct {
             cow
         } else {
-            Cow::Owned(
-                cow.chars()
-                    .filter(|c| !self.ignore_chars.contains(*c))
-                    .collect::<String>()
-                    .to_lowercase(),
-            )
+            let cow = normalization.compose(cow);
+            let w = cow
+                .chars()
+                .filter(|c| !ignore_chars.contains(*c))
+                .collect::<String>();
+            Cow::Owned(normalization.fold(case.fold(w)))
+        };
+
+        match self.stemmer() {
+            Some(l) => l.stem(w),
+            None => w,
+        }
+    }
+
+    fn stemmer(&self) -> Option<Language> {
+        match self.mode {
+            MatchMode::Regex | MatchMode::Glob => None,
+            _ => self.config.stemmer,
         }
     }
 }
@@ -265,4 +1582,546 @@ mod tests {
         p.set_mode(MatchMode::Inclusive);
         assert_eq!(p.match_str(text), true);
     }
+
+    #[test]
+    fn regex() {
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .words([r"^https?://\S+\.ru(/\S*)?$", r"^\+?\d{10,12}$"])
+            .try_build()
+            .unwrap();
+
+        assert!(p.match_str("visit https://free-skins.ru/now"));
+        assert!(p.match_str("visit HTTPS://FREE-SKINS.RU"));
+        assert!(!p.match_str("visit https://example.com"));
+        assert!(p.match_str("call +79991234567 now"));
+        assert!(!p.match_str("call 1234 now"));
+
+        // Expressions run on the written tokens, not the folded ones
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .case(CaseMode::Full)
+            .normalization(Normalization::all())
+            .words(["^straße$", r"^\+?\d{10,12}$"])
+            .try_build()
+            .unwrap();
+        assert!(p.match_str("Straße"));
+        assert!(p.match_str("call +79991234567 now"));
+
+        let invalid = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .words(["(unclosed"])
+            .try_build();
+        assert!(matches!(invalid, Err(PatternError::InvalidRegex { .. })));
+
+        for mode in [MatchMode::Inclusive, MatchMode::Prefix, MatchMode::Regex] {
+            let empty = MatchPattern::builder()
+                .mode(mode)
+                .ignore_chars("!")
+                .words(["scam", " ", "!!"])
+                .try_build();
+            assert!(matches!(empty, Err(PatternError::EmptyWord(w)) if w == " "));
+        }
+
+        // Each of them compiles, the set of them is over the size limit
+        let too_large = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .words((0..100).map(|i| format!(r"\w{{100}}{}", i)))
+            .try_build();
+        assert!(
+            matches!(too_large, Err(PatternError::InvalidRegex { word, .. }) if word.starts_with("set"))
+        );
+    }
+
+    #[test]
+    fn fuzzy() {
+        let mut p = MatchPattern::builder()
+            .mode(MatchMode::Fuzzy { max_distance: 1 })
+            .words(["scam", "giveaway"])
+            .build();
+
+        assert!(p.match_str("total scamm here"));
+        assert!(p.match_str("total sacm here"));
+        assert!(p.match_str("GIVEAWY now"));
+        assert!(!p.match_str("total scammmm here"));
+        assert!(!p.match_str("total sc here"));
+
+        p.set_mode(MatchMode::Fuzzy { max_distance: 2 });
+        assert!(p.match_str("free g1veawy"));
+        assert!(p.match_str("scammm"));
+        assert!(!p.match_str("free nitro"));
+    }
+
+    #[test]
+    fn phrases() {
+        let mut p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .words(["Free  Nitro", "scam"])
+            .build();
+        assert_eq!(p.terms, ["free nitro", "scam"]);
+
+        assert!(p.match_str("get FREE nitro here"));
+        assert!(p.match_str("get free,   nitro! here"));
+        assert!(p.match_str("get free - nitro here"));
+        assert!(!p.match_str("get free cool nitro here"));
+        assert!(!p.match_str("get freee nitro here"));
+        assert!(!p.match_str("nitro free"));
+
+        p.set_mode(MatchMode::Inclusive);
+        assert!(p.match_str("get carefree nitrogen here"));
+        assert!(!p.match_str("get free cool nitro here"));
+
+        assert!(p.remove("free nitro"));
+        assert!(!p.match_str("get free nitro here"));
+        assert_eq!(p.words(), &["scam"]);
+    }
+
+    #[test]
+    fn config() {
+        let mut p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .ignore_chars("*.")
+            .words(["s.c.a.m", "NFT"])
+            .build();
+        assert!(p.match_str("total s*c*a*m"));
+        assert!(p.match_str("buy nft"));
+
+        p.set_config(PatternConfig {
+            case: CaseMode::Sensitive,
+            min_token_len: Some(4),
+            ..p.config().clone()
+        });
+        assert!(!p.match_str("buy NFT"));
+        assert!(p.match_str("total s.c.a.m"));
+        assert!(!p.match_str("total S.C.A.M"));
+    }
+
+    #[test]
+    fn report() {
+        let text = "Get FREE nitro: https://nitro.ru now, totally not a SCAM";
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Inclusive)
+            .words(["scam", "free nitro", "nitro"])
+            .build();
+
+        assert!(p.match_report("nothing to see here").is_none());
+
+        let report = p.match_report(text).unwrap();
+        let hits = report
+            .hits
+            .iter()
+            .map(|h| (h.word.as_str(), &text[h.start..h.end]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hits,
+            [
+                ("free nitro", "FREE nitro"),
+                ("nitro", "nitro"),
+                ("nitro", "nitro"),
+                ("scam", "SCAM")
+            ]
+        );
+        assert!(report.hits.iter().all(|h| h.mode == MatchMode::Inclusive));
+
+        let p = MatchPattern::from_query("scam AND NOT legit", PatternConfig::default()).unwrap();
+        let report = p.match_report("scam, not legit").unwrap_or_default();
+        assert!(report.hits.is_empty());
+        let report = p.match_report("scam!").unwrap();
+        assert_eq!((report.hits[0].start, report.hits[0].end), (0, 4));
+    }
+
+    #[test]
+    fn weights() {
+        let p = MatchPattern::builder()
+            .words(["free", "nitro", "Steam", "gift"])
+            .weight("free", 0.5)
+            .weight("steam", 2.0)
+            .weight("GIFT", 0.25)
+            .threshold(1.5)
+            .build();
+
+        assert!(!p.match_str("free free free"));
+        assert!(p.match_report("free free free").is_none());
+        assert!(!p.match_str("a gift for free"));
+        assert!(p.match_str("free nitro"));
+        assert!(p.match_str("steam"));
+        assert_eq!(p.match_report("free nitro gift").unwrap().score, 1.75);
+        assert_eq!(p.match_report("STEAM gift").unwrap().score, 2.25);
+
+        let q =
+            MatchPattern::from_query("(free OR nitro) AND NOT legit", p.config().clone()).unwrap();
+        assert!(!q.match_str("free stuff"));
+        assert!(!q.match_str("free nitro, legit"));
+        assert!(q.match_str("free nitro, nitro!"));
+        assert_eq!(q.match_report("free nitro, nitro!").unwrap().score, 1.5);
+
+        // Words formatting to the same term are counted once
+        let p = MatchPattern::builder()
+            .words(["scam", "SCAM", "nitro"])
+            .threshold(2.0)
+            .build();
+        assert!(!p.match_str("scam"));
+        assert!(p.match_report("scam").is_none());
+        assert!(p.match_str("scam nitro"));
+        assert_eq!(p.match_report("SCAM nitro").unwrap().score, 2.0);
+    }
+
+    #[test]
+    fn conditions() {
+        use super::conditions::NameCondition;
+
+        let msg = |author: &str, channel: &str, message: &str| UserMessage {
+            author: author.to_owned(),
+            channel: channel.to_owned(),
+            message: message.to_owned(),
+        };
+
+        let p = MatchPattern::builder()
+            .conditions(Conditions {
+                author: Some(NameCondition::List(vec![
+                    "spammer".to_owned(),
+                    "bot".to_owned(),
+                ])),
+                ..Default::default()
+            })
+            .build();
+        assert!(p
+            .match_message(&msg("Spammer", "esl_dota2", "hi"))
+            .is_some());
+        assert!(p.match_message(&msg("viewer", "esl_dota2", "hi")).is_none());
+        assert!(MatchPattern::new()
+            .match_message(&msg("spammer", "esl_dota2", "hi"))
+            .is_none());
+
+        let p = MatchPattern::builder()
+            .words(["scam"])
+            .conditions(Conditions {
+                channel: Some(NameCondition::Exact("#esl_dota2".to_owned())),
+                ..Default::default()
+            })
+            .build();
+        assert!(p
+            .match_message(&msg("viewer", "esl_dota2", "a scam"))
+            .is_some());
+        assert!(p
+            .match_message(&msg("viewer", "esl_csgo", "a scam"))
+            .is_none());
+        assert!(p.match_message(&msg("viewer", "esl_dota2", "gg")).is_none());
+    }
+
+    #[test]
+    fn serialization() {
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Fuzzy { max_distance: 2 })
+            .ignore_chars(".")
+            .words(["s.c.a.m", "free nitro"])
+            .threshold(1.0)
+            .build();
+        let json = serde_json::to_string(&p).unwrap();
+        let q = serde_json::from_str::<MatchPattern>(&json).unwrap();
+        assert_eq!(PatternDef::from(&q), PatternDef::from(&p));
+        assert!(q.match_str("what a scma"));
+        assert!(q.match_str("free nitor"));
+
+        let p = MatchPattern::from_query("scam AND NOT legit", PatternConfig::default()).unwrap();
+        let json = serde_json::to_string(&p).unwrap();
+        let q = serde_json::from_str::<MatchPattern>(&json).unwrap();
+        assert_eq!(q.query().map(Query::source), Some("scam AND NOT legit"));
+
+        let bad = [
+            r#"{"version": 2, "words": ["scam"]}"#,
+            r#"{"version": 1, "words": ["(scam"], "mode": "Regex"}"#,
+            r#"{"version": 1, "words": ["scam"], "query": "scam"}"#,
+            r#"{"version": 1, "query": "scam AND"}"#,
+        ];
+        for json in bad {
+            assert!(
+                serde_json::from_str::<MatchPattern>(json).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn case_modes() {
+        let p = MatchPattern::builder()
+            .case(CaseMode::Sensitive)
+            .words(["NASA", "Straße"])
+            .build();
+        assert!(p.match_str("NASA leaks"));
+        assert!(!p.match_str("nasa leaks"));
+        assert!(!p.match_str("STRASSE"));
+
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .case(CaseMode::Full)
+            .words(["straße"])
+            .build();
+        assert!(p.match_str("die STRASSE"));
+        assert!(p.match_str("die Strasse"));
+
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .case(CaseMode::Turkic)
+            .words(["istanbul", "ılık"])
+            .build();
+        assert!(p.match_str("İSTANBUL"));
+        assert!(p.match_str("ILIK"));
+        assert!(!p.match_str("ISTANBUL"));
+
+        // Spans still point into the original message
+        let p = MatchPattern::builder()
+            .case(CaseMode::Turkic)
+            .words(["istanbul"])
+            .build();
+        let hit = &p.match_report("to İSTANBUL").unwrap().hits[0];
+        assert_eq!((hit.start, hit.end), (3, 12));
+    }
+
+    #[test]
+    fn stemming() {
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .stemmer(Language::Russian)
+            .words(["мошенник", "бесплатная раздача"])
+            .build();
+        assert!(p.match_str("опять эти МОШЕННИКИ"));
+        assert!(p.match_str("не верьте мошенникам"));
+        assert!(p.match_str("бесплатную раздачу скинов"));
+        assert!(!p.match_str("мошна"));
+
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .stemmer(Language::German)
+            .words(["Betrüger"])
+            .build();
+        assert!(p.match_str("Vorsicht vor Betrügern"));
+
+        // Expressions are matched against the tokens as they are
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Regex)
+            .stemmer(Language::English)
+            .words(["^scams$"])
+            .build();
+        assert!(p.match_str("scams"));
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut p = MatchPattern::builder()
+            .words(["scam", "ass"])
+            .exceptions(["scampi", "Classic"])
+            .build();
+        assert!(!p.match_str("fried scampi"));
+        assert!(!p.match_str("a CLASSIC, really"));
+        assert!(p.match_str("scampi scam"));
+        assert!(p.match_str("scampis"));
+
+        let report = p.match_report("classic scam").unwrap();
+        assert_eq!(report.hits.len(), 1);
+        assert_eq!((report.hits[0].start, report.hits[0].end), (8, 12));
+        assert_eq!(report.suppressed[0].word, "ass");
+        assert_eq!(report.score, 1.0);
+
+        assert_eq!(
+            p.remove_exceptions(&["scampi".to_owned(), "prawn".to_owned()]),
+            ["prawn"]
+        );
+        assert!(p.match_str("fried scampi"));
+
+        // Words of an exception only count in a row
+        let p = MatchPattern::builder()
+            .words(["scam"])
+            .exceptions(["not a scam"])
+            .build();
+        assert!(!p.match_str("this is NOT a scam!"));
+        assert!(p.match_str("this is a scam"));
+        assert!(p.match_str("not a joke, a scam"));
+
+        let p = MatchPattern::from_query("scam OR ass", PatternConfig::default()).unwrap();
+        let mut q = p.clone();
+        q.add_exceptions(vec!["classic".to_owned()]);
+        assert!(p.match_str("classic"));
+        assert!(!q.match_str("classic"));
+
+        let def = PatternDef::from(&q);
+        assert_eq!(def.exceptions, ["classic"]);
+        assert!(!MatchPattern::try_from(def).unwrap().match_str("classic"));
+
+        let mut p =
+            MatchPattern::from_query("giveaway AND link", PatternConfig::default()).unwrap();
+        p.set_exceptions(vec!["giveaways".to_owned()]);
+        assert!(!p.match_str("giveaways link"));
+        assert!(p.match_report("giveaways link").is_none());
+        assert!(p.match_str("giveaway link"));
+        let report = p.match_report("giveaways giveaway link").unwrap();
+        assert_eq!(report.hits.len(), 2);
+        assert_eq!(report.suppressed.len(), 1);
+    }
+
+    #[test]
+    fn edges_and_globs() {
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Prefix)
+            .words(["scam", "free nit"])
+            .build();
+        assert!(p.match_str("SCAMMERS!"));
+        assert!(!p.match_str("ascam"));
+        assert!(p.match_str("get free nitro"));
+        let hit = &p.match_report("(scammers)").unwrap().hits[0];
+        assert_eq!((hit.start, hit.end), (1, 5));
+
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Suffix)
+            .words(["coin"])
+            .build();
+        assert!(p.match_str("buy bitcoin."));
+        assert!(!p.match_str("coins"));
+        let hit = &p.match_report("bitcoin.").unwrap().hits[0];
+        assert_eq!((hit.start, hit.end), (3, 7));
+
+        let p = MatchPattern::builder()
+            .mode(MatchMode::Glob)
+            .words(["scam*", "*coin", "fr?e n*o"])
+            .build();
+        assert!(p.match_str("scammer"));
+        assert!(p.match_str("shitcoin!"));
+        assert!(p.match_str("FRAE NITRO"));
+        assert!(!p.match_str("ascam coins"));
+        assert_eq!(p.globs.len(), 3);
+
+        let q = MatchPattern::from_query("glob:*coin AND prefix:pump", PatternConfig::default())
+            .unwrap();
+        assert!(q.match_str("pumping a memecoin"));
+        assert!(!q.match_str("a memecoin"));
+    }
+
+    #[test]
+    fn diff() {
+        let a = PatternDef::from(MatchPattern::builder().words(["scam", "gift"]).build());
+        let b = PatternDef::from(
+            MatchPattern::builder()
+                .mode(MatchMode::Exclusive)
+                .ignore_chars(".")
+                .words(["scam", "nitro"])
+                .build(),
+        );
+        let diff = a.diff(&b);
+        assert_eq!(diff.added_words, ["nitro"]);
+        assert_eq!(diff.removed_words, ["gift"]);
+        let changed = diff
+            .changed
+            .iter()
+            .map(|c| c.setting.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(changed, ["config.ignore_chars", "mode"]);
+        assert!(a.diff(&a).is_empty());
+    }
+
+    #[test]
+    fn wordlist() {
+        let path = std::env::temp_dir().join(format!("chatspy-wordlist-{}", std::process::id()));
+        std::fs::write(&path, "# bots\nscam\nfree nitro\n").unwrap();
+
+        let p =
+            MatchPattern::from_file(&path, MatchMode::Inclusive, PatternConfig::default()).unwrap();
+        assert!(p.match_str("get FREE nitro"));
+        assert!(!p.match_str("steam gift"));
+        assert!(!p.wordlist().unwrap().is_stale());
+
+        std::fs::write(&path, "scam\nsteam gift\n").unwrap();
+        let p = p.reloaded().unwrap();
+        assert!(p.match_str("steam gift"));
+        assert!(!p.match_str("get FREE nitro"));
+
+        let def = PatternDef::from(&p);
+        assert!(def.words.is_empty());
+        assert!(MatchPattern::try_from(def).unwrap().match_str("steam gift"));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(p.reloaded().is_err());
+    }
+
+    #[test]
+    fn normalization() {
+        let mut p = MatchPattern::builder()
+            .mode(MatchMode::Exclusive)
+            .words(["FR33", "sсам"])
+            .build();
+        assert!(!p.match_str("ＦＲＥＥ skins"));
+        assert!(!p.match_str("total scam"));
+
+        p.set_normalization(Normalization::all());
+        assert_eq!(p.terms, ["free", "scam"]);
+        for m in [
+            "ＦＲＥＥ skins",
+            "fr€€ skins",
+            "ƒree skins",
+            "frée skins",
+            "total ЅСАМ",
+        ] {
+            assert!(p.match_str(m), "{}", m);
+        }
+        assert!(!p.match_str("freedom"));
+
+        // Leet chars around a word are left to be trimmed as punctuation
+        let normalized = |mode, words: &[&str]| {
+            MatchPattern::builder()
+                .mode(mode)
+                .normalization(Normalization::all())
+                .words(words.iter().copied())
+                .build()
+        };
+        assert!(normalized(MatchMode::Exclusive, &["free nitro"]).match_str("get fr33 nitro!"));
+        assert!(normalized(MatchMode::Suffix, &["coin"]).match_str("bitcoin!"));
+        assert!(normalized(MatchMode::Glob, &["*coin"]).match_str("bitc0in!"));
+        let mut p = normalized(MatchMode::Inclusive, &["scam"]);
+        p.set_exceptions(vec!["scampi".to_owned()]);
+        assert!(!p.match_str("fried scampi!"));
+        assert!(p.match_str("a scam!"));
+    }
+
+    #[test]
+    fn tokenize() {
+        let configs = [
+            PatternConfig::default(),
+            PatternConfig {
+                ignore_chars: ".!".to_owned(),
+                min_token_len: Some(3),
+                ..Default::default()
+            },
+            PatternConfig {
+                case: CaseMode::Sensitive,
+                stemmer: Some(Language::English),
+                ..Default::default()
+            },
+            PatternConfig {
+                ignore_chars: "@".to_owned(),
+                normalization: Normalization::all(),
+                ..Default::default()
+            },
+            PatternConfig {
+                case: CaseMode::Turkic,
+                normalization: Normalization::all(),
+                ..Default::default()
+            },
+        ];
+        let mut tokens = Tokens::default();
+        for config in configs {
+            let p = MatchPattern::builder().config(config).build();
+            for m in [
+                "KEKW that was INSANE!!! 1v5 @Streamer",
+                "  ЛОЛ   İstanbul FR33 skins... ",
+                "",
+            ] {
+                p.tokenize(m, &mut tokens);
+                let owned = |(span, w): (Range<usize>, &str)| (span, w.to_owned());
+                let expected = p.tokens(m).map(|(span, w)| (span, w.into_owned()));
+                assert!(tokens.iter().map(owned).eq(expected), "{}", m);
+                let expected = p.phrase_tokens(m).map(|(span, w)| (span, w.into_owned()));
+                assert!(tokens.trimmed(m).map(owned).eq(expected), "{}", m);
+            }
+        }
+    }
 }
@@ -0,0 +1,113 @@
+use caseless::Caseless;
+use serde::{Deserialize, Deserializer, Serialize};
+
+// How words and tokens are folded before they are compared. `Simple` maps
+// every char to its lowercase form, `Full` applies the Unicode case folding,
+// which also expands chars like `ß` into `ss`. `Turkic` lowercases the way
+// Turkish and Azerbaijani do, where `I` is the capital of the dotless `ı` and
+// `İ` the capital of `i`.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaseMode {
+    Sensitive,
+    #[default]
+    Simple,
+    Full,
+    Turkic,
+}
+
+impl CaseMode {
+    // Whether `c` is left as it is by the fold
+    pub(super) fn is_folded(self, c: char) -> bool {
+        if c.is_ascii() {
+            return self == CaseMode::Sensitive || !c.is_ascii_uppercase();
+        }
+        match self {
+            CaseMode::Sensitive => true,
+            CaseMode::Simple | CaseMode::Turkic => {
+                c != 'İ' && {
+                    let mut lower = c.to_lowercase();
+                    lower.next() == Some(c) && lower.next().is_none()
+                }
+            }
+            CaseMode::Full => std::iter::once(c)
+                .default_case_fold()
+                .eq(std::iter::once(c)),
+        }
+    }
+
+    pub(super) fn fold(self, w: String) -> String {
+        match self {
+            CaseMode::Sensitive => w,
+            CaseMode::Simple => w.to_lowercase(),
+            CaseMode::Full => caseless::default_case_fold_str(&w),
+            CaseMode::Turkic => w.replace('I', "ı").replace('İ', "i").to_lowercase(),
+        }
+    }
+}
+
+// Configs written before the modes were added only have a flag
+pub(super) fn deserialize_case_mode<'de, D: Deserializer<'de>>(d: D) -> Result<CaseMode, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Flag(bool),
+        Mode(CaseMode),
+    }
+
+    Ok(match Repr::deserialize(d)? {
+        Repr::Flag(true) => CaseMode::Sensitive,
+        Repr::Flag(false) => CaseMode::Simple,
+        Repr::Mode(m) => m,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold(mode: CaseMode, w: &str) -> String {
+        mode.fold(w.to_owned())
+    }
+
+    #[test]
+    fn scripts() {
+        // German sharp s only matches its expansion with the full fold
+        assert_eq!(fold(CaseMode::Simple, "STRASSE"), "strasse");
+        assert_eq!(fold(CaseMode::Simple, "Straße"), "straße");
+        assert_eq!(fold(CaseMode::Full, "Straße"), "strasse");
+        assert_eq!(fold(CaseMode::Full, "STRAẞE"), "strasse");
+        assert!(!CaseMode::Full.is_folded('ß'));
+        assert!(CaseMode::Simple.is_folded('ß'));
+
+        // Dotted and dotless i
+        assert_eq!(fold(CaseMode::Turkic, "İSTANBUL"), "istanbul");
+        assert_eq!(fold(CaseMode::Turkic, "ISPARTA"), "ısparta");
+        assert_eq!(fold(CaseMode::Simple, "ISPARTA"), "isparta");
+        assert_ne!(fold(CaseMode::Simple, "İSTANBUL"), "istanbul");
+        assert!(!CaseMode::Turkic.is_folded('İ'));
+        assert!(CaseMode::Turkic.is_folded('ı'));
+
+        // Final sigma folds to the regular one
+        assert_eq!(fold(CaseMode::Simple, "ΟΔΟΣ"), "οδος");
+        assert_eq!(fold(CaseMode::Full, "ΟΔΟΣ"), fold(CaseMode::Full, "οδοσ"));
+
+        assert_eq!(fold(CaseMode::Sensitive, "NASA"), "NASA");
+        assert!(CaseMode::Sensitive.is_folded('Ä'));
+        assert!(!CaseMode::Simple.is_folded('Ä'));
+        assert!(CaseMode::Simple.is_folded('7'));
+    }
+
+    #[test]
+    fn legacy_flag() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "deserialize_case_mode")]
+            case: CaseMode,
+        }
+
+        let parse = |json| serde_json::from_str::<Config>(json).unwrap().case;
+        assert_eq!(parse(r#"{"case": true}"#), CaseMode::Sensitive);
+        assert_eq!(parse(r#"{"case": false}"#), CaseMode::Simple);
+        assert_eq!(parse(r#"{"case": "Turkic"}"#), CaseMode::Turkic);
+    }
+}
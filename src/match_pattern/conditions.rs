@@ -0,0 +1,128 @@
+use crate::match_pattern::glob::Glob;
+use crate::twitch::UserMessage;
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+// Twitch logins are case insensitive and channels may be given with their `#`
+fn normalize_name(name: &str) -> Cow<'_, str> {
+    let name = name.trim_start_matches('#');
+    if name.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NameCondition {
+    Exact(String),
+    List(Vec<String>),
+    Glob(String),
+}
+
+// A condition with its names normalized and its glob compiled once, so that
+// checking a message only normalizes the name it has
+#[derive(Debug, Clone)]
+enum NameMatcher {
+    Exact(String),
+    List(FnvHashSet<String>),
+    Glob(Glob),
+}
+
+impl From<&NameCondition> for NameMatcher {
+    fn from(c: &NameCondition) -> Self {
+        match c {
+            NameCondition::Exact(n) => NameMatcher::Exact(normalize_name(n).into_owned()),
+            NameCondition::List(ns) => {
+                NameMatcher::List(ns.iter().map(|n| normalize_name(n).into_owned()).collect())
+            }
+            NameCondition::Glob(g) => NameMatcher::Glob(Glob::new(&normalize_name(g))),
+        }
+    }
+}
+
+impl NameMatcher {
+    fn matches(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        match self {
+            NameMatcher::Exact(n) => *n == name,
+            NameMatcher::List(ns) => ns.contains(&*name),
+            NameMatcher::Glob(g) => g.matches(&name),
+        }
+    }
+}
+
+// Restricts the messages a pattern is checked against, every condition which
+// is set has to hold
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Conditions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<NameCondition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<NameCondition>,
+}
+
+impl Conditions {
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none() && self.channel.is_none()
+    }
+}
+
+// What messages are checked against, built from `Conditions` when they are set
+#[derive(Debug, Clone, Default)]
+pub struct ConditionMatcher {
+    author: Option<NameMatcher>,
+    channel: Option<NameMatcher>,
+}
+
+impl From<&Conditions> for ConditionMatcher {
+    fn from(c: &Conditions) -> Self {
+        ConditionMatcher {
+            author: c.author.as_ref().map(NameMatcher::from),
+            channel: c.channel.as_ref().map(NameMatcher::from),
+        }
+    }
+}
+
+impl ConditionMatcher {
+    pub fn matches(&self, msg: &UserMessage) -> bool {
+        self.author.as_ref().is_none_or(|m| m.matches(&msg.author))
+            && self
+                .channel
+                .as_ref()
+                .is_none_or(|m| m.matches(&msg.channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let c = NameMatcher::from(&NameCondition::Glob("spam_bot*".to_owned()));
+        assert!(c.matches("spam_bot"));
+        assert!(c.matches("Spam_Bot_42"));
+        assert!(!c.matches("not_spam_bot"));
+
+        let c = NameMatcher::from(&NameCondition::Glob("*bot?".to_owned()));
+        assert!(c.matches("nightbot1"));
+        assert!(c.matches("bot_"));
+        assert!(!c.matches("nightbot"));
+        assert!(!c.matches("nightbot12"));
+
+        let c = NameMatcher::from(&NameCondition::List(vec![
+            "#ESL_Dota2".to_owned(),
+            "dota2ti".to_owned(),
+        ]));
+        assert!(c.matches("esl_dota2"));
+        assert!(c.matches("#dota2ti"));
+        assert!(!c.matches("esl_csgo"));
+
+        let c = NameMatcher::from(&NameCondition::Exact("Forsen".to_owned()));
+        assert!(c.matches("#FORSEN"));
+        assert!(!c.matches("forsen_"));
+    }
+}
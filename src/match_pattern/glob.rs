@@ -0,0 +1,97 @@
+// A glob split at its stars, which is all there is to parse: `*` matches any
+// sequence of chars and `?` a single one. Parts between two stars are matched
+// at their leftmost position, which never rules out a match a later one would
+// allow since they have a fixed length.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    parts: Vec<String>,
+}
+
+// Byte length of the start of `s` matched by `part`
+fn match_start(part: &str, s: &str) -> Option<usize> {
+    let mut len = 0;
+    let mut chars = s.chars();
+    for p in part.chars() {
+        match chars.next() {
+            Some(c) if p == '?' || p == c => len += c.len_utf8(),
+            _ => return None,
+        }
+    }
+    Some(len)
+}
+
+fn match_end(part: &str, s: &str) -> bool {
+    let mut chars = s.chars().rev();
+    part.chars()
+        .rev()
+        .all(|p| chars.next().is_some_and(|c| p == '?' || p == c))
+}
+
+impl Glob {
+    // Stars in a row leave empty parts between them, which match anywhere and
+    // are dropped. The first and the last part stay, they anchor the glob.
+    pub fn new(glob: &str) -> Self {
+        let n = glob.split('*').count();
+        Glob {
+            parts: glob
+                .split('*')
+                .enumerate()
+                .filter(|(i, part)| *i == 0 || *i == n - 1 || !part.is_empty())
+                .map(|(_, part)| part.to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        let (first, rest) = self.parts.split_first().unwrap();
+        let Some((last, middle)) = rest.split_last() else {
+            return match_start(first, s) == Some(s.len());
+        };
+
+        let Some(mut start) = match_start(first, s) else {
+            return false;
+        };
+        for part in middle {
+            let found = s[start..]
+                .char_indices()
+                .map(|(i, _)| start + i)
+                .find_map(|i| match_start(part, &s[i..]).map(|len| i + len));
+            match found {
+                Some(end) => start = end,
+                None => return false,
+            }
+        }
+        match_end(last, &s[start..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let cases = [
+            ("scam*", "scam", true),
+            ("scam*", "scammer", true),
+            ("scam*", "ascam", false),
+            ("*coin", "bitcoin", true),
+            ("*coin", "coins", false),
+            ("s?am", "sсam", true),
+            ("s?am", "scaam", false),
+            ("*n*t*o", "free_nitro", true),
+            ("a*b*c", "abbc", true),
+            ("a*b*c", "acb", false),
+            ("ab*ba", "aba", false),
+            ("*", "", true),
+            ("scam**", "scam", true),
+            ("s**m", "sm", true),
+            ("**", "", true),
+            ("", "", true),
+            ("??", "ж", false),
+        ];
+        for (glob, s, expected) in cases {
+            assert_eq!(Glob::new(glob).matches(s), expected, "{} {}", glob, s);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Lowercase Cyrillic, Greek and Latin extended letters which are rendered the
+// same way as a lowercase (or, for `в`, `м`, `н`, `т`, an uppercase) ASCII
+// letter. Uppercase letters are looked up by their lowercase form. Styled
+// forms of the letters (fullwidth, mathematical, circled...) are folded by
+// their Unicode compatibility decomposition instead. This is a pick of the
+// cross-script lookalikes seen in chat spam, not the full Unicode
+// confusables data, so rarer homoglyphs and symbols pass through unchanged.
+const CONFUSABLES: [(char, char); 47] = [
+    ('а', 'a'),
+    ('в', 'b'),
+    ('е', 'e'),
+    ('ѐ', 'e'),
+    ('һ', 'h'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('к', 'k'),
+    ('ӏ', 'l'),
+    ('м', 'm'),
+    ('н', 'h'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('ѕ', 's'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('ү', 'y'),
+    ('ѵ', 'v'),
+    ('х', 'x'),
+    ('ԁ', 'd'),
+    ('ԍ', 'g'),
+    ('ԛ', 'q'),
+    ('ԝ', 'w'),
+    ('հ', 'h'),
+    ('ո', 'n'),
+    ('ս', 'u'),
+    ('օ', 'o'),
+    ('α', 'a'),
+    ('β', 'b'),
+    ('ι', 'i'),
+    ('ϳ', 'j'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+    ('ɑ', 'a'),
+    ('ƅ', 'b'),
+    ('ƒ', 'f'),
+    ('ɡ', 'g'),
+    ('ı', 'i'),
+    ('ɩ', 'i'),
+    ('ʋ', 'u'),
+    ('ᴠ', 'v'),
+];
+
+const LEETSPEAK: [(char, char); 12] = [
+    ('0', 'o'),
+    ('1', 'i'),
+    ('3', 'e'),
+    ('4', 'a'),
+    ('5', 's'),
+    ('7', 't'),
+    ('8', 'b'),
+    ('@', 'a'),
+    ('$', 's'),
+    ('€', 'e'),
+    ('!', 'i'),
+    ('|', 'l'),
+];
+
+fn fold_confusable(c: char) -> char {
+    let lower = c.to_lowercase().next().unwrap_or(c);
+    CONFUSABLES
+        .iter()
+        .find(|(from, _)| *from == lower)
+        .map_or(c, |(_, to)| {
+            if lower == c {
+                *to
+            } else {
+                to.to_ascii_uppercase()
+            }
+        })
+}
+
+// `confusables` only folds the letters listed in `CONFUSABLES`
+// Byte range of `w` without the ASCII punctuation around it, nor the chars
+// which are not `kept`. Leet chars are only rewritten inside of it, so that
+// the `!` of `scam!` stays punctuation while `fr€€` still reads `free`.
+pub(super) fn leet_range(w: &str, kept: impl Fn(char) -> bool) -> Range<usize> {
+    let is_core = |c: char| !c.is_ascii_punctuation() && kept(c);
+    let start = w.find(is_core).unwrap_or(w.len());
+    let end = w[start..]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| is_core(*c))
+        .map_or(start, |(i, c)| start + i + c.len_utf8());
+    start..end
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Normalization {
+    pub nfkc: bool,
+    pub strip_diacritics: bool,
+    pub confusables: bool,
+    pub leetspeak: Option<BTreeMap<char, char>>,
+}
+
+impl Normalization {
+    pub fn all() -> Self {
+        Normalization {
+            nfkc: true,
+            strip_diacritics: true,
+            confusables: true,
+            leetspeak: Some(Normalization::default_leetspeak()),
+        }
+    }
+
+    pub fn default_leetspeak() -> BTreeMap<char, char> {
+        BTreeMap::from(LEETSPEAK)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.nfkc || self.strip_diacritics || self.confusables || self.leetspeak.is_some()
+    }
+
+    // Whether `c` passes through every enabled stage unchanged
+    pub(super) fn is_stable(&self, c: char) -> bool {
+        !self.is_enabled()
+            || c.is_ascii_alphabetic()
+                && !self.leetspeak.as_ref().is_some_and(|m| m.contains_key(&c))
+    }
+
+    // First stage, runs before ignored chars are dropped and the word is case
+    // folded, so that fullwidth or styled forms of them are caught too. Those
+    // are lookalikes as well, so it also runs for `confusables`.
+    pub(super) fn compose<'a>(&self, w: Cow<'a, str>) -> Cow<'a, str> {
+        if (self.nfkc || self.confusables) && !w.is_ascii() {
+            Cow::Owned(w.nfkc().collect())
+        } else {
+            w
+        }
+    }
+
+    // Second stage, runs on the case folded word
+    pub(super) fn fold(&self, w: String) -> String {
+        let w = if self.strip_diacritics && !w.is_ascii() {
+            w.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect()
+        } else {
+            w
+        };
+
+        if !self.confusables && self.leetspeak.is_none() {
+            return w;
+        }
+
+        let leet = leet_range(&w, |_| true);
+        w.char_indices()
+            .map(|(i, c)| {
+                let c = if self.confusables {
+                    fold_confusable(c)
+                } else {
+                    c
+                };
+                if leet.contains(&i) {
+                    self.leet(c)
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    pub(super) fn leet(&self, c: char) -> char {
+        match &self.leetspeak {
+            Some(m) => m.get(&c).copied().unwrap_or(c),
+            None => c,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(n: &Normalization, w: &str) -> String {
+        n.fold(n.compose(Cow::Borrowed(w)).to_lowercase())
+    }
+
+    #[test]
+    fn stages() {
+        let n = Normalization::all();
+        for w in ["ＦＲＥＥ", "fr€€", "ƒree", "FR33", "frée", "FRÉÉ"] {
+            assert_eq!(normalize(&n, w), "free", "{}", w);
+        }
+
+        let n = Normalization {
+            nfkc: true,
+            ..Default::default()
+        };
+        assert_eq!(normalize(&n, "ＦＲ33"), "fr33");
+
+        let n = Normalization {
+            leetspeak: Some(BTreeMap::from([('3', 'e')])),
+            ..Default::default()
+        };
+        assert_eq!(normalize(&n, "FR33 g1ft"), "free g1ft");
+        assert_eq!(normalize(&Normalization::all(), "$c4m!!"), "$cam!!");
+        assert_eq!(normalize(&Normalization::all(), "!!!"), "!!!");
+        assert!(!n.is_stable('3'));
+        assert!(n.is_stable('g'));
+    }
+
+    #[test]
+    fn cyrillic_lookalikes() {
+        let n = Normalization {
+            confusables: true,
+            ..Default::default()
+        };
+        // Every letter but the first one is Cyrillic
+        assert_eq!(normalize(&n, "sсам"), "scam");
+        assert_eq!(normalize(&n, "SСАМ"), "scam");
+        // Fully Cyrillic words spelling Latin ones
+        assert_eq!(normalize(&n, "САЅНАРР"), "cashapp");
+        assert_eq!(normalize(&n, "КЕКW"), "kekw");
+        assert_eq!(normalize(&n, "ԍіѵе"), "give");
+        assert_eq!(normalize(&n, "ӏоӏ"), "lol");
+        assert_eq!(normalize(&n, "օոӏу"), "only");
+        assert_eq!(normalize(&n, "𝐒𝐜𝐚𝐦 ⓢⓒⓐⓜ"), "scam scam");
+        // Letters without a Latin lookalike are left as they are
+        assert_eq!(normalize(&n, "ЖДИ"), "жди");
+    }
+}
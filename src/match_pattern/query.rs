@@ -0,0 +1,378 @@
+use super::{MatchHit, MatchMode, MatchPattern, PatternConfig};
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+// Grammar, from the lowest to the highest precedence:
+//
+//   or      := and ("OR" and)*
+//   and     := unary ("AND" unary)*
+//   unary   := "NOT" unary | primary
+//   primary := "(" or ")" | term
+//   term    := [mode ":"] (word | "\"" phrase "\"")
+//
+// Keywords are case insensitive, terms without a mode prefix are inclusive and
+// `fuzzy:` terms allow a single edit. Every term shares the same config, but
+// the threshold only applies to the whole query.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl QueryError {
+    fn new(column: usize, message: impl Into<String>) -> Self {
+        QueryError {
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Term(Box<MatchPattern>),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    fn matches(&self, s: &str) -> bool {
+        match self {
+            Expr::Term(p) => p.match_str(s),
+            Expr::Not(e) => !e.matches(s),
+            Expr::And(es) => es.iter().all(|e| e.matches(s)),
+            Expr::Or(es) => es.iter().any(|e| e.matches(s)),
+        }
+    }
+
+    // Terms under a negation never take part in a match, so they are skipped.
+    // Hits inside of exceptions are kept for the report to tell apart.
+    fn collect_hits(&self, s: &str, hits: &mut Vec<MatchHit>) {
+        match self {
+            Expr::Term(p) => hits.extend(p.all_hits(s).into_iter().map(|(_, h, _)| h)),
+            Expr::Not(_) => {}
+            Expr::And(es) | Expr::Or(es) => es.iter().for_each(|e| e.collect_hits(s, hits)),
+        }
+    }
+
+    fn set_exceptions(&mut self, exceptions: &[String]) {
+        match self {
+            Expr::Term(p) => p.set_exceptions(exceptions.to_vec()),
+            Expr::Not(e) => e.set_exceptions(exceptions),
+            Expr::And(es) | Expr::Or(es) => {
+                es.iter_mut().for_each(|e| e.set_exceptions(exceptions))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Query {
+    source: String,
+    root: Expr,
+}
+
+impl Query {
+    pub fn parse(source: &str, config: &PatternConfig) -> Result<Query, QueryError> {
+        let config = PatternConfig {
+            threshold: None,
+            ..config.clone()
+        };
+        let mut parser = Parser {
+            tokens: lex(source)?.into_iter().peekable(),
+            end: source.chars().count() + 1,
+            config: &config,
+        };
+
+        let root = parser.parse_or()?;
+        if let Some(t) = parser.peek() {
+            let message = match t.kind {
+                TokenKind::RParen => "unmatched ')'".to_owned(),
+                _ => format!("expected operator, found '{}'", t.lexeme),
+            };
+            return Err(QueryError::new(t.column, message));
+        }
+
+        Ok(Query {
+            source: source.to_owned(),
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        self.root.matches(s)
+    }
+
+    pub(super) fn set_exceptions(&mut self, exceptions: &[String]) {
+        self.root.set_exceptions(exceptions);
+    }
+
+    // Hits of the terms a matching message was caught by
+    pub fn hits(&self, s: &str) -> Option<Vec<MatchHit>> {
+        if !self.matches(s) {
+            return None;
+        }
+        let mut hits = vec![];
+        self.root.collect_hits(s, &mut hits);
+        Some(hits)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term(MatchMode, String),
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    lexeme: String,
+    column: usize,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')' || c == '"'
+}
+
+fn parse_mode(name: &str) -> Option<MatchMode> {
+    match name.to_lowercase().as_str() {
+        "inclusive" => Some(MatchMode::Inclusive),
+        "exclusive" => Some(MatchMode::Exclusive),
+        "regex" => Some(MatchMode::Regex),
+        "fuzzy" => Some(MatchMode::Fuzzy { max_distance: 1 }),
+        "prefix" => Some(MatchMode::Prefix),
+        "suffix" => Some(MatchMode::Suffix),
+        "glob" => Some(MatchMode::Glob),
+        _ => None,
+    }
+}
+
+// Reads a quoted phrase starting at `chars[start] == '"'`. Only `\"` is an
+// escape sequence, other backslashes are kept as they are for expressions.
+fn lex_quoted(chars: &[char], start: usize) -> Result<(String, usize), QueryError> {
+    let mut text = String::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '"' => return Ok((text, i + 1)),
+            '\\' if chars.get(i + 1) == Some(&'"') => {
+                text.push('"');
+                i += 2;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    Err(QueryError::new(start + 1, "unterminated quote"))
+}
+
+fn lex(source: &str) -> Result<Vec<Token>, QueryError> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let start = i;
+        let kind = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                TokenKind::LParen
+            }
+            ')' => {
+                i += 1;
+                TokenKind::RParen
+            }
+            '"' => {
+                let (text, next) = lex_quoted(&chars, i)?;
+                i = next;
+                TokenKind::Term(MatchMode::default(), text)
+            }
+            _ => {
+                while i < chars.len() && !is_delimiter(chars[i]) {
+                    i += 1;
+                }
+                let word = chars[start..i].iter().collect::<String>();
+
+                match word.split_once(':') {
+                    // `mode:"quoted phrase"`
+                    Some((name, "")) if chars.get(i) == Some(&'"') => {
+                        let mode = parse_mode(name).ok_or_else(|| {
+                            QueryError::new(start + 1, format!("unknown match mode '{}'", name))
+                        })?;
+                        let (text, next) = lex_quoted(&chars, i)?;
+                        i = next;
+                        TokenKind::Term(mode, text)
+                    }
+                    Some((name, "")) if parse_mode(name).is_some() => {
+                        return Err(QueryError::new(
+                            i + 1,
+                            format!("expected term after '{}'", word),
+                        ));
+                    }
+                    Some((name, text)) if parse_mode(name).is_some() => {
+                        TokenKind::Term(parse_mode(name).unwrap(), text.to_owned())
+                    }
+                    _ if word.eq_ignore_ascii_case("and") => TokenKind::And,
+                    _ if word.eq_ignore_ascii_case("or") => TokenKind::Or,
+                    _ if word.eq_ignore_ascii_case("not") => TokenKind::Not,
+                    _ => TokenKind::Term(MatchMode::default(), word),
+                }
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            lexeme: chars[start..i].iter().collect(),
+            column: start + 1,
+        });
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Peekable<IntoIter<Token>>,
+    end: usize,
+    config: &'a PatternConfig,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek()
+    }
+
+    fn eat(&mut self, kind: TokenKind) -> bool {
+        self.tokens.next_if(|t| t.kind == kind).is_some()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut es = vec![self.parse_and()?];
+        while self.eat(TokenKind::Or) {
+            es.push(self.parse_and()?);
+        }
+        Ok(if es.len() == 1 {
+            es.pop().unwrap()
+        } else {
+            Expr::Or(es)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut es = vec![self.parse_unary()?];
+        while self.eat(TokenKind::And) {
+            es.push(self.parse_unary()?);
+        }
+        Ok(if es.len() == 1 {
+            es.pop().unwrap()
+        } else {
+            Expr::And(es)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        if self.eat(TokenKind::Not) {
+            Ok(Expr::Not(Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, QueryError> {
+        let Some(t) = self.tokens.next() else {
+            return Err(QueryError::new(self.end, "unexpected end of query"));
+        };
+
+        match t.kind {
+            TokenKind::LParen => {
+                let e = self.parse_or()?;
+                if self.eat(TokenKind::RParen) {
+                    Ok(e)
+                } else {
+                    let end = self.end;
+                    let column = self.peek().map_or(end, |t| t.column);
+                    Err(QueryError::new(column, "expected ')'"))
+                }
+            }
+            TokenKind::Term(_, text) if text.trim().is_empty() => {
+                Err(QueryError::new(t.column, "empty term"))
+            }
+            TokenKind::Term(mode, text) => MatchPattern::builder()
+                .config(self.config.clone())
+                .mode(mode)
+                .words([text])
+                .try_build()
+                .map(|p| Expr::Term(Box::new(p)))
+                .map_err(|e| QueryError::new(t.column, e.to_string())),
+            _ => Err(QueryError::new(
+                t.column,
+                format!("expected term, found '{}'", t.lexeme),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluation() {
+        let config = PatternConfig::default();
+        let q = Query::parse(
+            r#"giveaway AND regex:"^https?://" AND NOT official"#,
+            &config,
+        )
+        .unwrap();
+        assert!(q.matches("GIVEAWAY at https://skins.example"));
+        assert!(!q.matches("official giveaway at https://skins.example"));
+        assert!(!q.matches("giveaway, no link"));
+
+        let q = Query::parse(r#"(scam OR "free nitro") and not exclusive:mod"#, &config).unwrap();
+        assert!(q.matches("get free nitro here"));
+        assert!(q.matches("scammers everywhere"));
+        assert!(!q.matches("mod says scam"));
+        assert!(q.matches("moderator says scam"));
+    }
+
+    #[test]
+    fn errors() {
+        let column = |s| {
+            Query::parse(s, &PatternConfig::default())
+                .unwrap_err()
+                .column
+        };
+        assert_eq!(column("a AND"), 6);
+        assert_eq!(column("a b"), 3);
+        assert_eq!(column("(a OR b"), 8);
+        assert_eq!(column("a OR b)"), 7);
+        assert_eq!(column(r#"a AND "b"#), 7);
+        assert_eq!(column(r#"a AND regex:"(x""#), 7);
+        assert_eq!(column("a AND regex: b"), 13);
+        assert_eq!(column(r#"a AND fuzz:"b""#), 7);
+        assert_eq!(column("OR a"), 1);
+    }
+}
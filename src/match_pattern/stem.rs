@@ -0,0 +1,77 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+// Snowball stemmers for the languages of the watched chats. There is none for
+// Estonian, Finnish is the closest one.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+    Russian,
+    Finnish,
+}
+
+impl Language {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::English => Algorithm::English,
+            Language::German => Algorithm::German,
+            Language::Russian => Algorithm::Russian,
+            Language::Finnish => Algorithm::Finnish,
+        }
+    }
+
+    // Only the word inside of the punctuation around it is stemmed, which is
+    // kept so that phrases can still trim it. Stemmers expect lowercase words.
+    pub(super) fn stem<'a>(self, w: Cow<'a, str>) -> Cow<'a, str> {
+        let is_edge = |c: char| !c.is_alphanumeric();
+        let core = w.trim_matches(is_edge);
+        if core.is_empty() {
+            return w;
+        }
+
+        let stemmed = Stemmer::create(self.algorithm()).stem(core);
+        if stemmed == core {
+            return w;
+        }
+        let start = w.len() - w.trim_start_matches(is_edge).len();
+        let end = start + core.len();
+        Cow::Owned(format!("{}{}{}", &w[..start], stemmed, &w[end..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stems(l: Language, words: &[&str]) -> Vec<String> {
+        words
+            .iter()
+            .map(|w| l.stem(Cow::Borrowed(w)).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn inflections() {
+        let forms = [
+            (Language::English, &["scam", "scams", "scamming"][..]),
+            (
+                Language::Russian,
+                &["мошенник", "мошенники", "мошенников", "мошенникам"],
+            ),
+            (Language::German, &["betrüger", "betrügers", "betrügern"]),
+            (
+                Language::Finnish,
+                &["huijari", "huijarin", "huijareita", "huijarille"],
+            ),
+        ];
+        for (l, words) in forms {
+            let stems = stems(l, words);
+            assert!(stems.iter().all(|s| *s == stems[0]), "{:?}", stems);
+        }
+
+        assert_eq!(stems(Language::English, &["(scams!)"]), ["(scam!)"]);
+        assert_eq!(stems(Language::English, &["!!!"]), ["!!!"]);
+    }
+}
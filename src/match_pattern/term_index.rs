@@ -0,0 +1,112 @@
+use fnv::{FnvHashMap, FnvHasher};
+use std::hash::Hasher;
+
+// Lists smaller than this fit in cache as a hash map, so a Bloom filter in
+// front of it would only add a second hash per token
+const BLOOM_MIN_TERMS: usize = 4096;
+const BLOOM_BITS_PER_TERM: usize = 16;
+const BLOOM_HASHES: u32 = 5;
+
+// Blocked Bloom filter: all bits of a term are set in the same 64 bit word,
+// so a lookup touches a single cache line
+#[derive(Debug, Clone)]
+struct Bloom {
+    blocks: Vec<u64>,
+}
+
+impl Bloom {
+    fn new(terms: usize) -> Self {
+        let blocks = (terms * BLOOM_BITS_PER_TERM / 64).next_power_of_two();
+        Bloom {
+            blocks: vec![0; blocks],
+        }
+    }
+
+    // Block index from the low half of the hash, bit positions from 6 bit
+    // chunks of the high half
+    fn locate(&self, w: &str) -> (usize, u64) {
+        let mut hasher = FnvHasher::default();
+        hasher.write(w.as_bytes());
+        let h = hasher.finish();
+        let block = (h as u32 as usize) & (self.blocks.len() - 1);
+        let mask = (0..BLOOM_HASHES).fold(0, |mask, i| mask | 1 << ((h >> (32 + i * 6)) & 63));
+        (block, mask)
+    }
+
+    fn insert(&mut self, w: &str) {
+        let (block, mask) = self.locate(w);
+        self.blocks[block] |= mask;
+    }
+
+    fn may_contain(&self, w: &str) -> bool {
+        let (block, mask) = self.locate(w);
+        self.blocks[block] & mask == mask
+    }
+}
+
+// Exact lookup of formatted single word terms, which is all exclusive mode
+// needs. Tokens with a length no term has are rejected before hashing, large
+// lists also go through a Bloom filter first, since most tokens of a message
+// are not in them.
+#[derive(Debug, Clone, Default)]
+pub(super) struct TermIndex {
+    ids: FnvHashMap<String, Vec<usize>>,
+    lens: Vec<bool>,
+    bloom: Option<Bloom>,
+}
+
+impl TermIndex {
+    pub(super) fn new<'a>(terms: impl IntoIterator<Item = (usize, &'a String)>) -> Self {
+        let mut index = TermIndex::default();
+        for (id, t) in terms {
+            let len = t.chars().count();
+            if index.lens.len() <= len {
+                index.lens.resize(len + 1, false);
+            }
+            index.lens[len] = true;
+            index.ids.entry(t.clone()).or_default().push(id);
+        }
+
+        if index.ids.len() >= BLOOM_MIN_TERMS {
+            let mut bloom = Bloom::new(index.ids.len());
+            index.ids.keys().for_each(|t| bloom.insert(t));
+            index.bloom = Some(bloom);
+        }
+        index
+    }
+
+    pub(super) fn get(&self, w: &str) -> &[usize] {
+        let has_len = self.lens.get(w.chars().count()).copied();
+        if !has_len.unwrap_or(false) || self.bloom.as_ref().is_some_and(|b| !b.may_contain(w)) {
+            return &[];
+        }
+        self.ids.get(w).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let terms = ["kek", "jopa", "kek", "лол"].map(String::from);
+        let index = TermIndex::new(terms.iter().enumerate());
+        assert_eq!(index.get("kek"), [0, 2]);
+        assert_eq!(index.get("лол"), [3]);
+        assert!(index.get("kekw").is_empty());
+        assert!(index.get("").is_empty());
+        assert!(index.bloom.is_none());
+
+        let terms = (0..BLOOM_MIN_TERMS * 2)
+            .map(|i| format!("term{}", i))
+            .collect::<Vec<_>>();
+        let index = TermIndex::new(terms.iter().enumerate());
+        assert!(index.bloom.is_some());
+        for (i, t) in terms.iter().enumerate() {
+            assert_eq!(index.get(t), [i]);
+        }
+        assert!(index.get("term").is_empty());
+        assert!(index.get("term-1").is_empty());
+    }
+}
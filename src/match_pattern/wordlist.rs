@@ -0,0 +1,56 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// One term per line, blank lines and lines starting with `#` are skipped
+pub fn parse_wordlist(s: &str) -> Vec<String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// File a pattern takes its words from, along with the modification time of
+// the version which was loaded
+#[derive(Debug, Clone)]
+pub struct Wordlist {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Wordlist {
+    pub(super) fn read(path: PathBuf) -> io::Result<(Wordlist, Vec<String>)> {
+        // Taken before reading, so a write in between is picked up next time
+        let modified = modified(&path);
+        let words = parse_wordlist(&std::fs::read_to_string(&path)?);
+        Ok((Wordlist { path, modified }, words))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_stale(&self) -> bool {
+        modified(&self.path) != self.modified
+    }
+
+    pub(super) fn mark_seen(&mut self) {
+        self.modified = modified(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let words = parse_wordlist("# spam bots\nfree nitro\n\n  scam  \n#scam2\r\nsteam gift\n");
+        assert_eq!(words, ["free nitro", "scam", "steam gift"]);
+    }
+}
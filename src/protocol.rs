@@ -1,9 +1,112 @@
-use crate::match_pattern::MatchMode;
+use crate::match_pattern::conditions::Conditions;
+use crate::match_pattern::{
+    MatchMode, MatchPattern, MatchReport, PatternConfig, PatternDef, PatternDiff,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 
 type Channels = Vec<String>;
-type RawPattern = (Vec<String>, MatchMode);
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum RawPattern {
+    Words(Vec<String>, MatchMode),
+    Query(String),
+    File(PathBuf, MatchMode),
+}
+
+impl From<&MatchPattern> for RawPattern {
+    fn from(p: &MatchPattern) -> Self {
+        match (p.query(), p.wordlist()) {
+            (Some(q), _) => RawPattern::Query(q.source().to_owned()),
+            (_, Some(w)) => RawPattern::File(w.path().to_owned(), p.mode()),
+            _ => RawPattern::Words(p.words().clone(), p.mode()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatternInfo {
+    pub name: String,
+    pub default: bool,
+    pub enabled: bool,
+    pub channels: Vec<String>,
+    pub raw_pattern: RawPattern,
+    pub config: PatternConfig,
+    pub conditions: Conditions,
+    pub exceptions: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ExportedPattern {
+    pub name: String,
+    pub pattern: PatternDef,
+}
+
+// `diff` is against the previous revision, the first one has none
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    pub time: String,
+    pub client: String,
+    pub diff: Option<PatternDiff>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum BacktestTarget {
+    Pattern(String),
+    Words(Vec<String>, MatchMode),
+}
+
+// Messages come from the `messages` table, or from a log of raw IRC lines
+// when `log` is set. Logs carry no time, so `since` only applies to the table.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BacktestAction {
+    pub target: BacktestTarget,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    pub samples: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BacktestSample {
+    pub channel: String,
+    pub author: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    pub report: MatchReport,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct BacktestReport {
+    pub checked: usize,
+    pub hits: usize,
+    pub channels: BTreeMap<String, usize>,
+    pub samples: Vec<BacktestSample>,
+}
+
+// `last_hit` is in seconds since the epoch
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HitStats {
+    pub hits: u64,
+    pub last_hit: Option<u64>,
+    pub channels: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatternStats {
+    #[serde(flatten)]
+    pub total: HitStats,
+    pub terms: BTreeMap<String, HitStats>,
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum PartAction {
@@ -22,6 +125,12 @@ pub enum AddAction {
     Pattern {
         name: String,
         raw_pattern: RawPattern,
+        #[serde(default)]
+        config: Box<PatternConfig>,
+        #[serde(default)]
+        conditions: Conditions,
+        #[serde(default)]
+        exceptions: Vec<String>,
         default: bool,
     },
 }
@@ -40,9 +149,86 @@ pub enum GetAction {
         channel: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         author: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        #[serde(default)]
+        by_score: bool,
     },
     Patterns,
     Channels,
+    Stats {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        #[serde(default)]
+        reset: bool,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum PatternAction {
+    Export {
+        name: String,
+    },
+    Import {
+        pattern: Box<ExportedPattern>,
+        default: bool,
+    },
+    Enable {
+        name: String,
+    },
+    Disable {
+        name: String,
+    },
+    Bind {
+        name: String,
+        channels: Channels,
+    },
+    Unbind {
+        name: String,
+        channels: Channels,
+    },
+    List,
+    Show {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    AddWords {
+        name: String,
+        words: Vec<String>,
+    },
+    RemoveWords {
+        name: String,
+        words: Vec<String>,
+    },
+    AddExceptions {
+        name: String,
+        words: Vec<String>,
+    },
+    RemoveExceptions {
+        name: String,
+        words: Vec<String>,
+    },
+    SetDefault {
+        name: String,
+    },
+    Revisions {
+        name: String,
+    },
+    Diff {
+        name: String,
+        from: u64,
+        to: u64,
+    },
+    Rollback {
+        name: String,
+        revision: u64,
+    },
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -50,18 +236,44 @@ pub enum Action {
     Twitch(TwitchAction),
     Add(AddAction),
     Get(GetAction),
+    Pattern(PatternAction),
+    Backtest(BacktestAction),
     Kill,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Error {
     JoinFail { channel: String },
+    InvalidPattern { name: String, reason: String },
+    UnknownPattern { name: String },
+    PatternExists { name: String },
+    UnknownWord { name: String, word: String },
+    UnknownException { name: String, word: String },
+    UnknownRevision { name: String, revision: u64 },
+    LogUnreadable { path: PathBuf, reason: String },
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::JoinFail { channel } => write!(f, "failed to join to channel: {}", channel),
+            Error::InvalidPattern { name, reason } => {
+                write!(f, "invalid pattern '{}': {}", name, reason)
+            }
+            Error::UnknownPattern { name } => write!(f, "no pattern named '{}'", name),
+            Error::PatternExists { name } => write!(f, "pattern '{}' already exists", name),
+            Error::UnknownWord { name, word } => {
+                write!(f, "pattern '{}' has no word '{}'", name, word)
+            }
+            Error::UnknownException { name, word } => {
+                write!(f, "pattern '{}' has no exception '{}'", name, word)
+            }
+            Error::UnknownRevision { name, revision } => {
+                write!(f, "pattern '{}' has no revision {}", name, revision)
+            }
+            Error::LogUnreadable { path, reason } => {
+                write!(f, "failed to read log '{}': {}", path.display(), reason)
+            }
         }
     }
 }
@@ -0,0 +1,196 @@
+use crate::match_pattern::MatchReport;
+use crate::protocol::{HitStats, PatternStats};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type Shared<T> = RwLock<FnvHashMap<String, T>>;
+
+// Maps are only locked for writing the first time a key is seen, the rest of
+// the updates are done on atomics under a read lock
+fn get_or_insert<T: Default>(map: &Shared<Arc<T>>, key: &str) -> Arc<T> {
+    if let Some(v) = map.read().unwrap().get(key) {
+        return v.clone();
+    }
+    map.write()
+        .unwrap()
+        .entry(key.to_owned())
+        .or_default()
+        .clone()
+}
+
+// Reads a counter, zeroing it in the same step on reset so that no hit
+// recorded in the meantime is lost
+fn take(c: &AtomicU64, reset: bool) -> u64 {
+    if reset {
+        c.swap(0, Ordering::Relaxed)
+    } else {
+        c.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    hits: AtomicU64,
+    // Seconds since the epoch, 0 when there was no hit yet
+    last_hit: AtomicU64,
+    channels: Shared<AtomicU64>,
+}
+
+impl Counter {
+    fn record(&self, channel: &str, now: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.last_hit.fetch_max(now, Ordering::Relaxed);
+
+        if let Some(c) = self.channels.read().unwrap().get(channel) {
+            c.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.channels
+            .write()
+            .unwrap()
+            .entry(channel.to_owned())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self, reset: bool) -> HitStats {
+        let last_hit = take(&self.last_hit, reset);
+        HitStats {
+            hits: take(&self.hits, reset),
+            last_hit: (last_hit > 0).then_some(last_hit),
+            channels: self
+                .channels
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(ch, c)| (ch.clone(), take(c, reset)))
+                .filter(|(_, hits)| *hits > 0)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PatternCounters {
+    total: Counter,
+    terms: Shared<Arc<Counter>>,
+}
+
+impl PatternCounters {
+    fn stats(&self, reset: bool) -> PatternStats {
+        PatternStats {
+            total: self.total.stats(reset),
+            terms: self
+                .terms
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(t, c)| (t.clone(), c.stats(reset)))
+                .filter(|(_, s)| s.hits > 0)
+                .collect(),
+        }
+    }
+}
+
+// Hits of every pattern since the daemon started or the last reset. A message
+// counts once for a pattern and once for each distinct term it caught.
+#[derive(Debug, Default)]
+pub struct Stats {
+    patterns: Shared<Arc<PatternCounters>>,
+}
+
+impl Stats {
+    pub fn record(&self, pattern: &str, channel: &str, report: &MatchReport) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let counters = get_or_insert(&self.patterns, pattern);
+        counters.total.record(channel, now);
+
+        let terms = report
+            .hits
+            .iter()
+            .map(|h| h.word.as_str())
+            .collect::<FnvHashSet<_>>();
+        for t in terms {
+            get_or_insert(&counters.terms, t).record(channel, now);
+        }
+    }
+
+    // Counters are zeroed in place on reset, so the workers keep recording
+    // into them and the map is only ever read here. Counters without hits
+    // are left out.
+    pub fn get(&self, pattern: Option<&str>, reset: bool) -> BTreeMap<String, PatternStats> {
+        let patterns_lock = self.patterns.read().unwrap();
+        let counters = match pattern {
+            Some(n) => patterns_lock
+                .get_key_value(n)
+                .map(|(n, c)| (n.clone(), c.clone()))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            None => patterns_lock
+                .iter()
+                .map(|(n, c)| (n.clone(), c.clone()))
+                .collect(),
+        };
+        drop(patterns_lock);
+
+        counters
+            .into_iter()
+            .map(|(n, c)| (n, c.stats(reset)))
+            .filter(|(_, s)| s.total.hits > 0)
+            .collect()
+    }
+
+    pub fn rename(&self, n: &str, new_n: &str) {
+        let mut patterns_lock = self.patterns.write().unwrap();
+        if let Some(c) = patterns_lock.remove(n) {
+            patterns_lock.insert(new_n.to_owned(), c);
+        }
+    }
+
+    pub fn remove(&self, n: &str) {
+        self.patterns.write().unwrap().remove(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_pattern::MatchPattern;
+
+    #[test]
+    fn stats() {
+        let p = MatchPattern::builder().words(["scam", "nitro"]).build();
+        let stats = Stats::default();
+        for (channel, msg) in [
+            ("forsen", "scam scam"),
+            ("xqc", "free nitro scam"),
+            ("xqc", "nitro"),
+        ] {
+            stats.record("spam", channel, &p.match_report(msg).unwrap());
+        }
+
+        let s = &stats.get(None, false)["spam"];
+        assert_eq!(s.total.hits, 3);
+        assert!(s.total.last_hit.is_some());
+        assert_eq!(s.total.channels["xqc"], 2);
+        assert_eq!(s.terms["scam"].hits, 2);
+        assert_eq!(s.terms["scam"].channels["forsen"], 1);
+        assert_eq!(s.terms["nitro"].hits, 2);
+
+        stats.rename("spam", "scam");
+        assert!(stats.get(Some("spam"), false).is_empty());
+        assert_eq!(stats.get(Some("scam"), true)["scam"].total.hits, 3);
+        assert!(stats.get(None, false).is_empty());
+
+        stats.record("scam", "forsen", &p.match_report("nitro").unwrap());
+        let s = &stats.get(None, false)["scam"];
+        assert_eq!(s.total.hits, 1);
+        assert_eq!(s.total.channels.len(), 1);
+        assert_eq!(s.terms.keys().collect::<Vec<_>>(), ["nitro"]);
+    }
+}
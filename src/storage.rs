@@ -1,5 +1,6 @@
+use crate::match_pattern::{MatchReport, PatternDef};
 use crate::twitch::UserMessage;
-use rusqlite::{params_from_iter, Connection, Error};
+use rusqlite::{params_from_iter, Connection, Error, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
 fn insert_twitch_message(_conn: &Connection, _message: TwitchMessage) {}
@@ -18,11 +19,58 @@ pub struct TwitchMessage {
     pub message: String,
     pub channel: String,
     pub time: String,
+    pub report: Option<MatchReport>,
+    pub score: Option<f64>,
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredPattern {
+    pub name: String,
+    pub definition: PatternDef,
+    pub default: bool,
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PatternRevision {
+    pub revision: u64,
+    pub time: String,
+    pub client: String,
+    // Definitions from a newer version may not deserialize, they are left
+    // for the caller to report
+    pub definition: Result<PatternDef, String>,
 }
 
 pub fn run_init_migration(conn: &Connection) {
     create_token_table(conn);
     create_messages_table(conn);
+    create_patterns_table(conn);
+    create_pattern_bindings_table(conn);
+    create_pattern_revisions_table(conn);
+    add_column_if_missing(conn, "messages", "report", "TEXT");
+    add_column_if_missing(conn, "messages", "score", "REAL");
+    add_column_if_missing(conn, "messages", "pattern", "TEXT");
+}
+
+// Tables created by older versions are missing the columns added since
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) {
+    let exists = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name=?2",
+            (table, column),
+            |row| row.get::<_, u64>(0),
+        )
+        .unwrap()
+        > 0;
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            (),
+        )
+        .unwrap();
+    }
 }
 
 pub fn create_token_table(conn: &Connection) {
@@ -66,7 +114,10 @@ pub fn create_messages_table(conn: &Connection) {
         author  TEXT NOT NULL,\
         message TEXT NOT NULL,\
         channel TEXT NOT NULL,\
-        time    TIMESTAMP DATETIME DEFAULT CURRENT_TIMESTAMP\
+        time    TIMESTAMP DATETIME DEFAULT CURRENT_TIMESTAMP,\
+        report  TEXT,\
+        score   REAL,\
+        pattern TEXT\
         )",
         (),
     ) {
@@ -74,10 +125,24 @@ pub fn create_messages_table(conn: &Connection) {
     }
 }
 
-pub fn insert_message(conn: &Connection, privmsg: UserMessage) {
+// A message caught by several patterns is stored once for each of them
+pub fn insert_message(
+    conn: &Connection,
+    privmsg: &UserMessage,
+    pattern: &str,
+    report: &MatchReport,
+) {
     conn.execute(
-        "INSERT INTO messages (author, message, channel) VALUES (?1, ?2, ?3)",
-        (privmsg.author, privmsg.message, privmsg.channel),
+        "INSERT INTO messages (author, message, channel, report, score, pattern) \
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (
+            &privmsg.author,
+            &privmsg.message,
+            &privmsg.channel,
+            serde_json::to_string(report).unwrap(),
+            report.score,
+            pattern,
+        ),
     )
     .unwrap();
 }
@@ -86,18 +151,27 @@ pub fn get_messages(
     conn: &Connection,
     author: Option<String>,
     channel: Option<String>,
+    pattern: Option<String>,
+    by_score: bool,
 ) -> Vec<TwitchMessage> {
-    let (sql, params) = match (author, channel) {
-        (None, None) => ("SELECT * FROM messages", vec![]),
-        (Some(a), Some(ch)) => (
-            "SELECT * FROM messages WHERE author=?1 AND channel=?2",
-            vec![a, ch],
-        ),
-        (_, Some(ch)) => ("SELECT * FROM messages WHERE channel=?1", vec![ch]),
-        (Some(a), _) => ("SELECT * FROM messages WHERE author=?1", vec![a]),
-    };
+    let mut sql = "SELECT * FROM messages".to_owned();
+    let mut params = vec![];
+    for (column, value) in [
+        ("author", author),
+        ("channel", channel),
+        ("pattern", pattern),
+    ] {
+        if let Some(v) = value {
+            params.push(v);
+            let op = if params.len() == 1 { "WHERE" } else { "AND" };
+            sql.push_str(&format!(" {} {}=?{}", op, column, params.len()));
+        }
+    }
+    if by_score {
+        sql.push_str(" ORDER BY score DESC");
+    }
 
-    match conn.prepare(sql) {
+    match conn.prepare(&sql) {
         Ok(mut s) => s
             .query_map(params_from_iter(params), |row| {
                 Ok(TwitchMessage {
@@ -106,6 +180,12 @@ pub fn get_messages(
                     message: row.get(2).unwrap(),
                     channel: row.get(3).unwrap(),
                     time: row.get(4).unwrap(),
+                    report: row
+                        .get::<_, Option<String>>(5)
+                        .unwrap()
+                        .and_then(|r| serde_json::from_str(&r).ok()),
+                    score: row.get(6).unwrap(),
+                    pattern: row.get(7).unwrap(),
                 })
             })
             .unwrap()
@@ -115,6 +195,261 @@ pub fn get_messages(
     }
 }
 
+// A message caught by several patterns is visited once, along with the time
+// it was stored at
+pub fn for_each_message(
+    conn: &Connection,
+    since: Option<&str>,
+    mut f: impl FnMut(UserMessage, String),
+) {
+    let mut s = match conn.prepare(
+        "SELECT DISTINCT author, message, channel, time FROM messages \
+        WHERE ?1 IS NULL OR time >= ?1 ORDER BY time",
+    ) {
+        Ok(s) => s,
+        Err(e) => panic!("ERROR: failed to select from 'messages': {}", e),
+    };
+    let mut rows = s.query((since,)).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let msg = UserMessage {
+            author: row.get(0).unwrap(),
+            message: row.get(1).unwrap(),
+            channel: row.get(2).unwrap(),
+        };
+        f(msg, row.get(3).unwrap());
+    }
+}
+
+// The words and config of a pattern are kept in its definition, which is
+// versioned and validated again when it is loaded
+pub fn create_patterns_table(conn: &Connection) {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE patterns (\
+        name       TEXT PRIMARY KEY,\
+        definition TEXT NOT NULL,\
+        is_default INTEGER NOT NULL DEFAULT 0,\
+        enabled    INTEGER NOT NULL DEFAULT 1\
+        )",
+        (),
+    ) {
+        ignore_table_exists_error(e);
+    }
+}
+
+pub fn create_pattern_bindings_table(conn: &Connection) {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE pattern_bindings (\
+        channel TEXT NOT NULL,\
+        pattern TEXT NOT NULL,\
+        PRIMARY KEY (channel, pattern)\
+        )",
+        (),
+    ) {
+        ignore_table_exists_error(e);
+    }
+}
+
+// Every definition a pattern had, numbered from 1 for each pattern along with
+// the client which set it
+pub fn create_pattern_revisions_table(conn: &Connection) {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE pattern_revisions (\
+        pattern    TEXT NOT NULL,\
+        revision   INTEGER NOT NULL,\
+        time       TIMESTAMP DATETIME DEFAULT CURRENT_TIMESTAMP,\
+        client     TEXT NOT NULL,\
+        definition TEXT NOT NULL,\
+        PRIMARY KEY (pattern, revision)\
+        )",
+        (),
+    ) {
+        ignore_table_exists_error(e);
+    }
+}
+
+pub fn insert_pattern(conn: &Connection, p: &StoredPattern) {
+    conn.execute(
+        "INSERT OR REPLACE INTO patterns (name, definition, is_default, enabled) \
+        VALUES (?1, ?2, ?3, ?4)",
+        (
+            &p.name,
+            serde_json::to_string(&p.definition).unwrap(),
+            p.default,
+            p.enabled,
+        ),
+    )
+    .unwrap();
+}
+
+pub fn update_pattern_definition(conn: &Connection, name: &str, definition: &PatternDef) {
+    conn.execute(
+        "UPDATE patterns SET definition=?2 WHERE name=?1",
+        (name, serde_json::to_string(definition).unwrap()),
+    )
+    .unwrap();
+}
+
+// Returns the number of the new revision
+pub fn insert_revision(
+    conn: &Connection,
+    name: &str,
+    client: &str,
+    definition: &PatternDef,
+) -> u64 {
+    conn.query_row(
+        "INSERT INTO pattern_revisions (pattern, revision, client, definition) \
+        SELECT ?1, COALESCE(MAX(revision), 0) + 1, ?2, ?3 FROM pattern_revisions WHERE pattern=?1 \
+        RETURNING revision",
+        (name, client, serde_json::to_string(definition).unwrap()),
+        |row| row.get(0),
+    )
+    .unwrap()
+}
+
+// Updates the definition and records it as a revision in one transaction
+pub fn save_pattern_revision(
+    conn: &Connection,
+    name: &str,
+    client: &str,
+    definition: &PatternDef,
+) -> u64 {
+    let tx = conn.unchecked_transaction().unwrap();
+    update_pattern_definition(&tx, name, definition);
+    let revision = insert_revision(&tx, name, client, definition);
+    tx.commit().unwrap();
+    revision
+}
+
+pub fn get_revisions(conn: &Connection, name: &str) -> Vec<PatternRevision> {
+    match conn.prepare(
+        "SELECT revision, time, client, definition FROM pattern_revisions \
+        WHERE pattern=?1 ORDER BY revision",
+    ) {
+        Ok(mut s) => s
+            .query_map((name,), revision_from_row)
+            .unwrap()
+            .map(|u| u.unwrap())
+            .collect(),
+        Err(e) => panic!("ERROR: failed to select from 'pattern_revisions': {}", e),
+    }
+}
+
+pub fn get_revision(conn: &Connection, name: &str, revision: u64) -> Option<PatternRevision> {
+    conn.query_row(
+        "SELECT revision, time, client, definition FROM pattern_revisions \
+        WHERE pattern=?1 AND revision=?2",
+        (name, revision),
+        revision_from_row,
+    )
+    .optional()
+    .unwrap()
+}
+
+fn revision_from_row(row: &rusqlite::Row) -> rusqlite::Result<PatternRevision> {
+    Ok(PatternRevision {
+        revision: row.get(0)?,
+        time: row.get(1)?,
+        client: row.get(2)?,
+        definition: parse_definition(&row.get::<_, String>(3)?),
+    })
+}
+
+fn parse_definition(json: &str) -> Result<PatternDef, String> {
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+pub fn update_pattern_enabled(conn: &Connection, name: &str, enabled: bool) {
+    conn.execute(
+        "UPDATE patterns SET enabled=?2 WHERE name=?1",
+        (name, enabled),
+    )
+    .unwrap();
+}
+
+// Unsets the flag of every other pattern
+pub fn update_default_pattern(conn: &Connection, name: Option<&str>) {
+    conn.execute("UPDATE patterns SET is_default=(name IS ?1)", (name,))
+        .unwrap();
+}
+
+pub fn rename_pattern(conn: &Connection, name: &str, new_name: &str) {
+    conn.execute(
+        "UPDATE patterns SET name=?2 WHERE name=?1",
+        (name, new_name),
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE pattern_bindings SET pattern=?2 WHERE pattern=?1",
+        (name, new_name),
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE pattern_revisions SET pattern=?2 WHERE pattern=?1",
+        (name, new_name),
+    )
+    .unwrap();
+}
+
+pub fn delete_pattern(conn: &Connection, name: &str) {
+    conn.execute("DELETE FROM patterns WHERE name=?1", (name,))
+        .unwrap();
+    conn.execute("DELETE FROM pattern_bindings WHERE pattern=?1", (name,))
+        .unwrap();
+    conn.execute("DELETE FROM pattern_revisions WHERE pattern=?1", (name,))
+        .unwrap();
+}
+
+// Rows whose definition doesn't deserialize come back as their name and the
+// reason
+pub fn get_patterns(conn: &Connection) -> Vec<Result<StoredPattern, (String, String)>> {
+    match conn.prepare("SELECT name, definition, is_default, enabled FROM patterns") {
+        Ok(mut s) => s
+            .query_map([], |row| {
+                let name: String = row.get(0).unwrap();
+                Ok(match parse_definition(&row.get::<_, String>(1).unwrap()) {
+                    Ok(definition) => Ok(StoredPattern {
+                        name,
+                        definition,
+                        default: row.get(2).unwrap(),
+                        enabled: row.get(3).unwrap(),
+                    }),
+                    Err(reason) => Err((name, reason)),
+                })
+            })
+            .unwrap()
+            .map(|u| u.unwrap())
+            .collect(),
+        Err(e) => panic!("ERROR: failed to select from 'patterns': {}", e),
+    }
+}
+
+pub fn insert_binding(conn: &Connection, channel: &str, pattern: &str) {
+    conn.execute(
+        "INSERT OR IGNORE INTO pattern_bindings (channel, pattern) VALUES (?1, ?2)",
+        (channel, pattern),
+    )
+    .unwrap();
+}
+
+pub fn delete_binding(conn: &Connection, channel: &str, pattern: &str) {
+    conn.execute(
+        "DELETE FROM pattern_bindings WHERE channel=?1 AND pattern=?2",
+        (channel, pattern),
+    )
+    .unwrap();
+}
+
+pub fn get_bindings(conn: &Connection) -> Vec<(String, String)> {
+    match conn.prepare("SELECT channel, pattern FROM pattern_bindings") {
+        Ok(mut s) => s
+            .query_map([], |row| Ok((row.get(0).unwrap(), row.get(1).unwrap())))
+            .unwrap()
+            .map(|u| u.unwrap())
+            .collect(),
+        Err(e) => panic!("ERROR: failed to select from 'pattern_bindings': {}", e),
+    }
+}
+
 fn ignore_table_exists_error(e: Error) {
     match e {
         // Error has values which indicate that this is 'table already exists' error
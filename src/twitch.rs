@@ -361,7 +361,10 @@ pub fn parse_privmsg(s: &str) -> Option<UserMessage> {
 
     let author = prefix.next()?.split('!').next()?.to_owned();
     let _ = prefix.next()?;
-    let channel = prefix.next()?[1..].to_owned();
+    let channel = prefix.next()?.strip_prefix('#')?.to_owned();
+    if channel.is_empty() {
+        return None;
+    }
 
     let message = splitn.next()?.to_owned();
 
@@ -371,3 +374,21 @@ pub fn parse_privmsg(s: &str) -> Option<UserMessage> {
         message,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privmsg() {
+        let m = parse_privmsg(":kek!kek@kek.tmi.twitch.tv PRIVMSG #forsen :hi :)").unwrap();
+        assert_eq!(
+            (&*m.author, &*m.channel, &*m.message),
+            ("kek", "forsen", "hi :)")
+        );
+
+        for s in ["a:b c  :d", ":x y жж :hi", ":x y # :hi", ":x y", ""] {
+            assert!(parse_privmsg(s).is_none(), "{}", s);
+        }
+    }
+}